@@ -0,0 +1,169 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{httpdate::format_clf_date, log, request::Request, status::StatusCode};
+
+/// Where access log lines go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessLog {
+    Disabled,
+    Stdout,
+    /// Appended to, creating the file if needed.
+    File(PathBuf),
+}
+
+/// Writes one line per handled request in NCSA Common Log Format, followed
+/// by the quoted request ID.
+pub struct AccessLogger {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLogger {
+    /// Opens the configured target, or returns `None` when logging is disabled.
+    pub fn open(target: &AccessLog) -> io::Result<Option<AccessLogger>> {
+        let out: Box<dyn Write + Send> = match target {
+            AccessLog::Disabled => return Ok(None),
+            AccessLog::Stdout => Box::new(io::stdout()),
+            AccessLog::File(path) => {
+                Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+        };
+        Ok(Some(AccessLogger::new(out)))
+    }
+
+    pub fn new(out: Box<dyn Write + Send>) -> AccessLogger {
+        AccessLogger {
+            out: Mutex::new(out),
+        }
+    }
+
+    /// Records a response of `bytes` body bytes. `request` is `None` when the
+    /// request couldn't be parsed.
+    pub fn record(
+        &self,
+        remote_addr: Option<SocketAddr>,
+        request: Option<&Request>,
+        request_id: &str,
+        status: StatusCode,
+        bytes: usize,
+    ) {
+        let line = format_entry(
+            remote_addr,
+            request,
+            request_id,
+            status,
+            bytes,
+            SystemTime::now(),
+        );
+        let mut out = self.out.lock().unwrap_or_else(|err| err.into_inner());
+        if let Err(err) = writeln!(out, "{line}").and_then(|_| out.flush()) {
+            log::error!("Failed to write access log: {err}");
+        }
+    }
+}
+
+fn format_entry(
+    remote_addr: Option<SocketAddr>,
+    request: Option<&Request>,
+    request_id: &str,
+    status: StatusCode,
+    bytes: usize,
+    time: SystemTime,
+) -> String {
+    let host = remote_addr.map_or("-".to_string(), |addr| addr.ip().to_string());
+    let request_line = request.map_or("-".to_string(), |request| {
+        format!("{} {} {}", request.method, request.target, request.version)
+    });
+    let bytes = match bytes {
+        0 => "-".to_string(),
+        bytes => bytes.to_string(),
+    };
+    format!(
+        "{host} - - [{}] \"{request_line}\" {} {bytes} \"{request_id}\"",
+        format_clf_date(time),
+        status.as_u16(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::*;
+
+    /// A writer whose output stays readable after it's boxed up.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn get(target: &str) -> Request {
+        let raw = format!("GET {target} HTTP/1.1\r\n\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn entry_fields_are_in_common_log_format_order() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let entry = format_entry(
+            Some("192.0.2.7:51000".parse().unwrap()),
+            Some(&get("/index.html?x=1")),
+            "req-1",
+            StatusCode::OK,
+            1234,
+            time,
+        );
+        assert_eq!(
+            entry,
+            "192.0.2.7 - - [06/Nov/1994:08:49:37 +0000] \"GET /index.html?x=1 HTTP/1.1\" \
+             200 1234 \"req-1\""
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_dashes() {
+        let entry = format_entry(None, None, "req-2", StatusCode::BAD_REQUEST, 0, UNIX_EPOCH);
+        assert!(entry.starts_with("- - - ["), "{entry}");
+        assert!(entry.ends_with("] \"-\" 400 - \"req-2\""), "{entry}");
+    }
+
+    #[test]
+    fn records_one_line_per_request() {
+        let out = SharedBuf::default();
+        let logger = AccessLogger::new(Box::new(out.clone()));
+        logger.record(None, Some(&get("/a")), "req-3", StatusCode::OK, 5);
+        logger.record(None, Some(&get("/b")), "req-4", StatusCode::NOT_FOUND, 0);
+
+        let written = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = written.lines().collect();
+        assert_eq!(lines.len(), 2, "{written}");
+        assert!(
+            lines[0].contains("\"GET /a HTTP/1.1\" 200 5"),
+            "{}",
+            lines[0]
+        );
+        assert!(
+            lines[1].contains("\"GET /b HTTP/1.1\" 404 -"),
+            "{}",
+            lines[1]
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! HTTP Basic authentication for selected path prefixes.
+
+use crate::{
+    base64, files::path_under_prefix, request::Request, response::Response, status::StatusCode,
+};
+
+/// Credentials required for requests under any of `path_prefixes`.
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    pub realm: String,
+    pub username: String,
+    pub password: String,
+    /// A prefix covers itself and everything below it, so `/admin` protects
+    /// `/admin` and `/admin/users` but not `/administrator`. Request paths
+    /// are percent-decoded and their `.`, `..` and empty segments resolved
+    /// first, as the file server does.
+    pub path_prefixes: Vec<String>,
+}
+
+impl BasicAuth {
+    /// Whether `path` falls under one of the protected prefixes.
+    pub fn protects(&self, path: &str) -> bool {
+        self.path_prefixes
+            .iter()
+            .any(|prefix| path_under_prefix(path, prefix))
+    }
+
+    /// Checks the request's `Authorization: Basic` header against the
+    /// configured credentials.
+    pub fn authorized(&self, request: &Request) -> bool {
+        let Some(value) = request.header("Authorization") else {
+            return false;
+        };
+        let Some((scheme, encoded)) = value.split_once(' ') else {
+            return false;
+        };
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return false;
+        }
+        let Some(decoded) = base64::decode(encoded.trim()) else {
+            return false;
+        };
+        let expected = format!("{}:{}", self.username, self.password);
+        constant_time_eq(&decoded, expected.as_bytes())
+    }
+
+    /// Middleware for `Router::wrap` that answers `401 Unauthorized` to
+    /// unauthenticated requests for protected paths.
+    pub fn middleware(
+        self,
+    ) -> impl Fn(&Request, &dyn Fn(&Request) -> Response) -> Response + Send + Sync + 'static {
+        move |request, next| {
+            if !self.protects(&request.path) || self.authorized(request) {
+                return next(request);
+            }
+            let realm = self.realm.replace(['\\', '"'], "");
+            Response::error(StatusCode::UNAUTHORIZED)
+                .header("WWW-Authenticate", format!("Basic realm=\"{realm}\""))
+        }
+    }
+}
+
+/// Compares without returning early on the first difference, so the
+/// response time doesn't reveal how much of a guess was right.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut difference = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        difference |= usize::from(x ^ y);
+    }
+    difference == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> BasicAuth {
+        BasicAuth {
+            realm: "admin".to_string(),
+            username: "user".to_string(),
+            password: "pass".to_string(),
+            path_prefixes: vec!["/admin".to_string()],
+        }
+    }
+
+    fn request(path: &str, authorization: Option<&str>) -> Request {
+        let mut raw = format!("GET {path} HTTP/1.1\r\n");
+        if let Some(authorization) = authorization {
+            raw.push_str(&format!("Authorization: {authorization}\r\n"));
+        }
+        raw.push_str("\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn respond(request: &Request) -> Response {
+        let middleware = auth().middleware();
+        middleware(request, &|_| Response::new(StatusCode::OK))
+    }
+
+    #[test]
+    fn correct_credentials_are_let_through() {
+        // "user:pass"
+        let response = respond(&request("/admin/x", Some("Basic dXNlcjpwYXNz")));
+        assert_eq!(response.status, StatusCode::OK);
+    }
+
+    #[test]
+    fn wrong_credentials_are_rejected() {
+        // "user:nope"
+        let response = respond(&request("/admin/x", Some("Basic dXNlcjpub3Bl")));
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn missing_credentials_get_a_challenge() {
+        let response = respond(&request("/admin/x", None));
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.header_value("WWW-Authenticate"),
+            Some("Basic realm=\"admin\"")
+        );
+    }
+
+    #[test]
+    fn unprotected_paths_need_no_credentials() {
+        let response = respond(&request("/administrator", None));
+        assert_eq!(response.status, StatusCode::OK);
+    }
+
+    #[test]
+    fn encoded_and_dotted_paths_are_still_protected() {
+        let auth = auth();
+        for path in [
+            "/admin",
+            "/admin/",
+            "/%61dmin/secret.txt",
+            "//admin/secret.txt",
+            "/./admin/secret.txt",
+            "/public/../admin/secret.txt",
+            "/admin%2Fsecret.txt",
+            "/%61dmin/%ZZ",
+        ] {
+            assert!(auth.protects(path), "{path} should be protected");
+        }
+        for path in ["/", "/administrator", "/public/admin", "/admin/../public"] {
+            assert!(!auth.protects(path), "{path} should not be protected");
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Standard base64 (RFC 4648 with `+`, `/` and `=` padding), just enough
+//! for `Authorization` headers and WebSocket handshakes.
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes with padding.
+pub(crate) fn encode(input: &[u8]) -> String {
+    let mut encoded = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes padded base64, returning `None` for anything malformed.
+pub(crate) fn decode(input: &str) -> Option<Vec<u8>> {
+    let input = input.as_bytes();
+    if !input.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(input.len() / 4 * 3);
+    let chunks = input.len() / 4;
+    for (index, chunk) in input.chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|&&b| b == b'=').count();
+        if padding > 2 || (padding > 0 && index + 1 != chunks) {
+            return None;
+        }
+        let mut group = 0u32;
+        for &byte in &chunk[..4 - padding] {
+            group = group << 6 | u32::from(sextet(byte)?);
+        }
+        group <<= 6 * padding as u32;
+        let bytes = [(group >> 16) as u8, (group >> 8) as u8, group as u8];
+        decoded.extend_from_slice(&bytes[..3 - padding]);
+    }
+    Some(decoded)
+}
+
+fn sextet(byte: u8) -> Option<u8> {
+    ALPHABET
+        .iter()
+        .position(|&b| b == byte)
+        .map(|position| position as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_with_padding() {
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(b"f"), "Zg==");
+        assert_eq!(encode(b"fo"), "Zm8=");
+        assert_eq!(encode(b"foo"), "Zm9v");
+        assert_eq!(
+            encode(b"Aladdin:open sesame"),
+            "QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+        );
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        for input in [&b""[..], b"f", b"fo", b"foo", b"\x00\xff\x10"] {
+            assert_eq!(decode(&encode(input)).as_deref(), Some(input));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert_eq!(decode("Zm9"), None);
+        assert_eq!(decode("Zm9v!A=="), None);
+        assert_eq!(decode("Zg==Zm9v"), None);
+        assert_eq!(decode("Z==="), None);
+    }
+}
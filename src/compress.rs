@@ -0,0 +1,247 @@
+use std::io::Write;
+
+use flate2::{read, write::GzEncoder, Compression};
+
+use crate::{log, request::Request, response::Response};
+
+/// Gzips `response` in place when the client accepts it and the body is a
+/// text type of at least `min_bytes`. Bodies that are already compressed,
+/// such as images, partial content and chunked bodies are left alone.
+///
+/// A body streamed from a file is compressed as it's sent, and goes out
+/// chunked since its compressed length isn't known up front. The gzipped
+/// body is a different representation, so its `ETag` gets a `-gzip`
+/// suffix, and every response that could have been gzipped carries `Vary:
+/// Accept-Encoding` whether it was or not, so caches keep the two apart.
+pub fn gzip_response(request: &Request, response: &mut Response, min_bytes: usize) {
+    let len = if response.is_streamed() {
+        response
+            .header_value("Content-Length")
+            .and_then(|len| len.parse().ok())
+    } else {
+        Some(response.body.len() as u64)
+    };
+    if len.is_none_or(|len| len < min_bytes as u64)
+        || response.header_value("Content-Encoding").is_some()
+        || response.header_value("Content-Range").is_some()
+        || !response
+            .header_value("Content-Type")
+            .is_some_and(is_compressible)
+    {
+        return;
+    }
+    response
+        .headers
+        .push(("Vary".into(), "Accept-Encoding".into()));
+    if !accepts_gzip(request) {
+        return;
+    }
+
+    if let Some(reader) = response.take_sized_stream() {
+        response.set_chunked(Box::new(read::GzEncoder::new(
+            reader,
+            Compression::default(),
+        )));
+        mark_gzipped(response);
+        return;
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let compressed = match encoder
+        .write_all(&response.body)
+        .and_then(|_| encoder.finish())
+    {
+        Ok(compressed) => compressed,
+        Err(err) => {
+            log::error!("Failed to gzip response: {err}");
+            return;
+        }
+    };
+    response.body = compressed;
+    response.remove_header("Content-Length");
+    mark_gzipped(response);
+}
+
+fn mark_gzipped(response: &mut Response) {
+    // Ranges would have to count bytes of the gzipped body, which differs
+    // from one compression to the next.
+    response.remove_header("Accept-Ranges");
+    response
+        .headers
+        .push(("Content-Encoding".into(), "gzip".into()));
+    if let Some(etag) = response.header_value("ETag").map(gzip_etag) {
+        response.remove_header("ETag");
+        response.headers.push(("ETag".into(), etag));
+    }
+}
+
+/// Marks the entity tag of an uncompressed body as that of its gzipped
+/// form: `"abc"` becomes `"abc-gzip"`.
+fn gzip_etag(etag: &str) -> String {
+    match etag.strip_suffix('"') {
+        Some(opaque) => format!("{opaque}{GZIP_ETAG_SUFFIX}\""),
+        None => etag.to_string(),
+    }
+}
+
+const GZIP_ETAG_SUFFIX: &str = "-gzip";
+
+/// Undoes `gzip_etag`, for comparing a tag the client got with a gzipped
+/// body against the file's own.
+pub(crate) fn strip_gzip_etag(etag: &str) -> String {
+    match etag
+        .strip_suffix('"')
+        .and_then(|opaque| opaque.strip_suffix(GZIP_ETAG_SUFFIX))
+    {
+        Some(opaque) => format!("{opaque}\""),
+        None => etag.to_string(),
+    }
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    mime.starts_with("text/")
+        || matches!(
+            mime,
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        )
+}
+
+/// Whether `Accept-Encoding` lists `gzip` (or `*`) without `q=0`.
+fn accepts_gzip(request: &Request) -> bool {
+    request
+        .header_all("Accept-Encoding")
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or("");
+            let rejected = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !rejected
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::{
+        files::serve_path,
+        status::StatusCode,
+        test_support::{dechunk, split_head, TempDir},
+    };
+
+    fn get(accept_encoding: &str) -> Request {
+        let raw = format!("GET /big.txt HTTP/1.1\r\nAccept-Encoding: {accept_encoding}\r\n\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn gunzip(bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        GzDecoder::new(bytes).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn large_streamed_file_is_gzipped() {
+        let root = TempDir::new();
+        let text = "All work and no play makes Jack a dull boy.\n".repeat(5000);
+        root.write("big.txt", &text);
+        let request = get("gzip");
+        let mut response = serve_path(root.path(), "/big.txt", &request);
+        assert!(response.is_streamed());
+
+        gzip_response(&request, &mut response, 1024);
+        assert_eq!(response.header_value("Content-Encoding"), Some("gzip"));
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let (head, body) = split_head(&out);
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{head}");
+        assert!(!head.contains("Content-Length"), "{head}");
+        let compressed = dechunk(body);
+        assert!(compressed.len() < text.len() / 10);
+        assert_eq!(gunzip(&compressed), text.as_bytes());
+    }
+
+    #[test]
+    fn in_memory_text_is_gzipped_with_a_matching_content_length() {
+        let text = "<p>lorem ipsum dolor sit amet</p>\n".repeat(100);
+        let request = get("deflate, gzip");
+        let mut response = Response::new(StatusCode::OK)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(text.clone());
+        gzip_response(&request, &mut response, 1024);
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let (head, body) = split_head(&out);
+        assert!(head.contains("Content-Encoding: gzip\r\n"), "{head}");
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert_eq!(gunzip(body), text.as_bytes());
+    }
+
+    #[test]
+    fn images_and_refused_gzip_are_left_alone() {
+        let png = Response::new(StatusCode::OK)
+            .header("Content-Type", "image/png")
+            .body(vec![0; 4096]);
+        let text = Response::new(StatusCode::OK)
+            .header("Content-Type", "text/plain")
+            .body("a".repeat(4096));
+        for (accept_encoding, mut response) in [("gzip", png), ("gzip;q=0, br", text)] {
+            gzip_response(&get(accept_encoding), &mut response, 1024);
+            assert_eq!(response.header_value("Content-Encoding"), None);
+            assert_eq!(response.body.len(), 4096);
+        }
+    }
+
+    fn small_text(accept_encoding: &str) -> (Request, Response) {
+        let root = TempDir::new();
+        root.write("big.txt", "hello, hello, hello\n".repeat(200));
+        let request = get(accept_encoding);
+        let response = serve_path(root.path(), "/big.txt", &request);
+        (request, response)
+    }
+
+    #[test]
+    fn gzipped_body_gets_its_own_etag() {
+        let (request, mut response) = small_text("gzip");
+        let etag = response.header_value("ETag").unwrap().to_string();
+        gzip_response(&request, &mut response, 1024);
+
+        assert_eq!(response.header_value("Content-Encoding"), Some("gzip"));
+        let gzip_tag = response.header_value("ETag").unwrap();
+        assert_ne!(gzip_tag, etag);
+        assert!(gzip_tag.ends_with("-gzip\""), "{gzip_tag}");
+        assert_eq!(strip_gzip_etag(gzip_tag), etag);
+        assert_eq!(
+            gunzip(&response.body),
+            "hello, hello, hello\n".repeat(200).as_bytes()
+        );
+    }
+
+    #[test]
+    fn uncompressed_but_eligible_response_varies_on_accept_encoding() {
+        let (request, mut response) = small_text("identity");
+        let etag = response.header_value("ETag").unwrap().to_string();
+        gzip_response(&request, &mut response, 1024);
+
+        assert_eq!(response.header_value("Content-Encoding"), None);
+        assert_eq!(response.header_value("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.header_value("ETag"), Some(etag.as_str()));
+    }
+
+    #[test]
+    fn ineligible_response_does_not_vary() {
+        let (request, mut response) = small_text("gzip");
+        gzip_response(&request, &mut response, usize::MAX);
+        assert_eq!(response.header_value("Vary"), None);
+        assert_eq!(response.header_value("Content-Encoding"), None);
+    }
+}
@@ -0,0 +1,145 @@
+//! Cross-origin resource sharing for browser clients on other origins.
+
+use crate::{
+    request::{Method, Request},
+    response::Response,
+    status::StatusCode,
+};
+
+#[derive(Debug, Clone)]
+pub struct Cors {
+    /// Origins such as `https://app.example.com` that may make requests.
+    /// `*` allows any origin.
+    pub allowed_origins: Vec<String>,
+    /// Advertised in answer to preflight requests.
+    pub allowed_methods: Vec<Method>,
+    /// Request headers the browser may send, advertised in answer to
+    /// preflight requests.
+    pub allowed_headers: Vec<String>,
+}
+
+impl Cors {
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    /// Middleware for `Router::wrap`.
+    ///
+    /// Requests from an allowed `Origin` get it reflected in
+    /// `Access-Control-Allow-Origin`. Preflight `OPTIONS` requests from one
+    /// are answered with `204 No Content` without reaching the route. Other
+    /// origins get no CORS headers, which makes the browser block them.
+    pub fn middleware(
+        self,
+    ) -> impl Fn(&Request, &dyn Fn(&Request) -> Response) -> Response + Send + Sync + 'static {
+        move |request, next| {
+            let Some(origin) = request
+                .header("Origin")
+                .filter(|origin| self.allows_origin(origin))
+            else {
+                return next(request);
+            };
+            let preflight = request.method == Method::Options
+                && request.header("Access-Control-Request-Method").is_some();
+            if !preflight {
+                return next(request)
+                    .header("Access-Control-Allow-Origin", origin)
+                    .header("Vary", "Origin");
+            }
+
+            let methods = self
+                .allowed_methods
+                .iter()
+                .map(Method::as_str)
+                .collect::<Vec<_>>()
+                .join(", ");
+            Response::new(StatusCode::NO_CONTENT)
+                .header("Access-Control-Allow-Origin", origin)
+                .header("Access-Control-Allow-Methods", methods)
+                .header(
+                    "Access-Control-Allow-Headers",
+                    self.allowed_headers.join(", "),
+                )
+                .header("Vary", "Origin")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::router::Router;
+
+    /// A router behind CORS for `https://app.example.com`, and a flag set
+    /// whenever its route runs.
+    fn cors_router() -> (Router, Arc<AtomicBool>) {
+        let reached = Arc::new(AtomicBool::new(false));
+        let mut router = Router::new();
+        let flag = reached.clone();
+        router
+            .route(Method::Get, "/api", move |_| {
+                flag.store(true, Ordering::SeqCst);
+                Response::new(StatusCode::OK)
+            })
+            .wrap(
+                Cors {
+                    allowed_origins: vec!["https://app.example.com".to_string()],
+                    allowed_methods: vec![Method::Get, Method::Post],
+                    allowed_headers: vec!["Content-Type".to_string()],
+                }
+                .middleware(),
+            );
+        (router, reached)
+    }
+
+    fn request(method: &str, headers: &str) -> Request {
+        let raw = format!("{method} /api HTTP/1.1\r\n{headers}\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn allowed_origin_is_reflected() {
+        let (router, _) = cors_router();
+        let response = router.dispatch(&request("GET", "Origin: https://app.example.com\r\n"));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(
+            response.header_value("Access-Control-Allow-Origin"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(response.header_value("Vary"), Some("Origin"));
+    }
+
+    #[test]
+    fn other_origins_get_no_cors_headers() {
+        let (router, _) = cors_router();
+        let response = router.dispatch(&request("GET", "Origin: https://evil.example\r\n"));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.header_value("Access-Control-Allow-Origin"), None);
+    }
+
+    #[test]
+    fn preflight_is_answered_without_the_route() {
+        let (router, reached) = cors_router();
+        let response = router.dispatch(&request(
+            "OPTIONS",
+            "Origin: https://app.example.com\r\nAccess-Control-Request-Method: POST\r\n",
+        ));
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.header_value("Access-Control-Allow-Methods"),
+            Some("GET, POST")
+        );
+        assert_eq!(
+            response.header_value("Access-Control-Allow-Headers"),
+            Some("Content-Type")
+        );
+        assert!(!reached.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,169 @@
+//! An in-memory cache of small static files, shared by every worker.
+
+use std::{
+    collections::HashMap,
+    fs::{self, Metadata},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::SystemTime,
+};
+
+/// Holds the contents of recently served files, up to a total size, and
+/// evicts the least recently used ones to make room.
+///
+/// Entries are checked against the file's modification time and length on
+/// every lookup, so an edited file is read afresh.
+#[derive(Debug)]
+pub struct FileCache {
+    capacity_bytes: usize,
+    inner: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    files: HashMap<PathBuf, Entry>,
+    used_bytes: usize,
+    /// Bumped on every lookup; an entry's `last_used` orders evictions.
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    contents: Arc<Vec<u8>>,
+    modified: Option<SystemTime>,
+    last_used: u64,
+}
+
+impl FileCache {
+    pub fn new(capacity_bytes: usize) -> FileCache {
+        FileCache {
+            capacity_bytes,
+            inner: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Returns the contents of `path`, whose current metadata is
+    /// `metadata`, from the cache when the entry is still fresh and from
+    /// disk otherwise. Files larger than the whole cache are never kept.
+    pub(crate) fn read(&self, path: &Path, metadata: &Metadata) -> io::Result<Arc<Vec<u8>>> {
+        let modified = metadata.modified().ok();
+        {
+            let mut entries = self.lock();
+            entries.clock += 1;
+            let clock = entries.clock;
+            if let Some(entry) = entries.files.get_mut(path) {
+                if entry.modified == modified && entry.contents.len() as u64 == metadata.len() {
+                    entry.last_used = clock;
+                    return Ok(entry.contents.clone());
+                }
+            }
+        }
+
+        // Read without holding the lock so a slow disk doesn't stall
+        // workers serving other files.
+        let contents = Arc::new(fs::read(path)?);
+        if contents.len() <= self.capacity_bytes {
+            let mut entries = self.lock();
+            entries.remove(path);
+            while entries.used_bytes + contents.len() > self.capacity_bytes {
+                entries.evict_oldest();
+            }
+            entries.used_bytes += contents.len();
+            let last_used = entries.clock;
+            entries.files.insert(
+                path.to_path_buf(),
+                Entry {
+                    contents: contents.clone(),
+                    modified,
+                    last_used,
+                },
+            );
+        }
+        Ok(contents)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Entries {
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.files.remove(path) {
+            self.used_bytes -= entry.contents.len();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .files
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(path, _)| path.clone());
+        if let Some(path) = oldest {
+            self.remove(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn read(cache: &FileCache, path: &Path) -> Arc<Vec<u8>> {
+        cache.read(path, &fs::metadata(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn second_read_comes_from_the_cache() {
+        let dir = TempDir::new();
+        let path = dir.write("a.txt", "hello");
+        let metadata = fs::metadata(&path).unwrap();
+        let cache = FileCache::new(1024);
+
+        let first = cache.read(&path, &metadata).unwrap();
+        // With the file gone only the cache could still answer.
+        fs::remove_file(&path).unwrap();
+        let second = cache.read(&path, &metadata).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(*second, b"hello");
+    }
+
+    #[test]
+    fn changed_files_are_read_again() {
+        let dir = TempDir::new();
+        let path = dir.write("a.txt", "old");
+        let cache = FileCache::new(1024);
+        assert_eq!(*read(&cache, &path), b"old");
+
+        dir.write("a.txt", "newer");
+        assert_eq!(*read(&cache, &path), b"newer");
+        assert_eq!(cache.lock().used_bytes, 5);
+    }
+
+    #[test]
+    fn least_recently_used_files_are_evicted() {
+        let dir = TempDir::new();
+        let [a, b, c] = ["a", "b", "c"].map(|name| dir.write(name, "1234"));
+        let cache = FileCache::new(10);
+        let cached_a = read(&cache, &a);
+        let cached_b = read(&cache, &b);
+        read(&cache, &a);
+        read(&cache, &c);
+
+        assert!(Arc::ptr_eq(&cached_a, &read(&cache, &a)));
+        assert!(!Arc::ptr_eq(&cached_b, &read(&cache, &b)));
+        assert!(cache.lock().used_bytes <= 10);
+    }
+
+    #[test]
+    fn files_larger_than_the_cache_are_not_kept() {
+        let dir = TempDir::new();
+        let path = dir.write("big.txt", "0123456789");
+        let cache = FileCache::new(4);
+        assert_eq!(*read(&cache, &path), b"0123456789");
+        assert!(cache.lock().files.is_empty());
+    }
+}
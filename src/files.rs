@@ -0,0 +1,899 @@
+use std::{
+    fmt,
+    fs::{self, File, Metadata},
+    io::{self, ErrorKind, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    compress::strip_gzip_etag,
+    file_cache::FileCache,
+    httpdate::{format_http_date, parse_http_date},
+    log,
+    percent::{percent_decode, percent_decode_lossy, percent_encode, DecodeError},
+    request::{Method, Request},
+    response::Response,
+    status::StatusCode,
+};
+
+#[derive(Debug)]
+pub enum ResolveError {
+    NotFound,
+    /// The request path isn't validly percent-encoded.
+    InvalidPath(DecodeError),
+    /// The request path resolved to a location outside the document root.
+    Forbidden,
+    Io(io::Error),
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::NotFound => write!(f, "file not found"),
+            ResolveError::InvalidPath(err) => write!(f, "invalid path: {err}"),
+            ResolveError::Forbidden => write!(f, "path escapes the document root"),
+            ResolveError::Io(err) => write!(f, "io error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+impl From<io::Error> for ResolveError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::NotFound => ResolveError::NotFound,
+            _ => ResolveError::Io(err),
+        }
+    }
+}
+
+/// Maps a percent-encoded request path onto a file or directory under `root`.
+///
+/// The joined path is canonicalized so that `..` segments (including
+/// encoded ones) and symlinks are resolved before checking that the result
+/// is still inside `root`.
+pub fn resolve_path(root: &Path, request_path: &str) -> Result<PathBuf, ResolveError> {
+    let decoded = percent_decode(request_path).map_err(ResolveError::InvalidPath)?;
+    if decoded.contains('\0') {
+        return Err(ResolveError::NotFound);
+    }
+    let root = root.canonicalize()?;
+    let relative = decoded.trim_start_matches('/');
+    let resolved = root.join(relative).canonicalize()?;
+    if !resolved.starts_with(&root) {
+        return Err(ResolveError::Forbidden);
+    }
+    if !resolved.is_file() && !resolved.is_dir() {
+        return Err(ResolveError::NotFound);
+    }
+    Ok(resolved)
+}
+
+pub fn content_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+/// How `serve_path_with_options` handles what it finds under the document root.
+#[derive(Debug, Clone, Default)]
+pub struct ServeOptions {
+    /// Render an HTML listing for directories without an `index.html`
+    /// instead of answering `403 Forbidden`.
+    pub directory_listing: bool,
+    /// Keep small files in memory instead of reading them on every request.
+    pub cache: Option<Arc<FileCache>>,
+    /// Answer paths that match no file with the document root's
+    /// `index.html`, so a single-page app can route them itself. Paths
+    /// whose last segment has an extension, which are most likely missing
+    /// assets, and paths under `spa_api_prefixes` still get the 404.
+    pub spa_fallback: bool,
+    /// Matched like `BasicAuth::path_prefixes`.
+    pub spa_api_prefixes: Vec<String>,
+    /// Answers paths that match no file instead of `404.html`.
+    pub not_found: Option<NotFoundHandler>,
+}
+
+/// A handler for requests that match nothing, shared by every worker.
+#[derive(Clone)]
+pub struct NotFoundHandler(Arc<dyn Fn(&Request) -> Response + Send + Sync + 'static>);
+
+impl NotFoundHandler {
+    pub fn new<H>(handler: H) -> Self
+    where
+        H: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        NotFoundHandler(Arc::new(handler))
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        (self.0)(request)
+    }
+}
+
+impl fmt::Debug for NotFoundHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NotFoundHandler")
+    }
+}
+
+/// Serves the file that `path` resolves to under `document_root`, answering
+/// with the 404 page or an error status when it can't be served.
+pub fn serve_path(document_root: &Path, path: &str, request: &Request) -> Response {
+    serve_path_with_options(document_root, path, request, &ServeOptions::default())
+}
+
+/// Like `serve_path`. Directories are answered with their `index.html`, or
+/// failing that a listing when `options` allows one; a directory path
+/// without a trailing slash is first redirected to one with it.
+pub fn serve_path_with_options(
+    document_root: &Path,
+    path: &str,
+    request: &Request,
+    options: &ServeOptions,
+) -> Response {
+    let head_only = request.method == Method::Head;
+    let result = resolve_path(document_root, path).and_then(|resolved| {
+        if resolved.is_dir() && !path.ends_with('/') {
+            Ok(redirect_to_slash(path, request))
+        } else if resolved.is_dir() {
+            serve_directory(&resolved, request, options)
+        } else {
+            Ok(serve_file(&resolved, request, options.cache.as_deref())?)
+        }
+    });
+    match result {
+        Ok(response) => response,
+        Err(ResolveError::NotFound) if spa_route(path, options) => {
+            let index = document_root.join("index.html");
+            serve_file(&index, request, options.cache.as_deref()).unwrap_or_else(|_| match &options
+                .not_found
+            {
+                Some(handler) => handler.handle(request),
+                None => not_found(document_root, head_only),
+            })
+        }
+        Err(ResolveError::NotFound) => match &options.not_found {
+            Some(handler) => handler.handle(request),
+            None => not_found(document_root, head_only),
+        },
+        Err(ResolveError::InvalidPath(_)) => Response::new(StatusCode::BAD_REQUEST),
+        Err(ResolveError::Forbidden) => Response::new(StatusCode::FORBIDDEN),
+        Err(ResolveError::Io(err)) => {
+            log::error!("Failed to serve {path}: {err}");
+            Response::error(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Whether a missing `path` should get the single-page app's index.
+fn spa_route(path: &str, options: &ServeOptions) -> bool {
+    let last_segment = path.rsplit('/').next().unwrap_or("");
+    let api = options
+        .spa_api_prefixes
+        .iter()
+        .any(|prefix| path_under_prefix(path, prefix));
+    options.spa_fallback && !last_segment.contains('.') && !api
+}
+
+/// Whether `path` is `prefix` itself or lies below it, as the path
+/// prefixes of `BasicAuth`, `ProxyHandler` and `ServeOptions` are matched.
+///
+/// Both are compared segment by segment after normalizing them the way
+/// `resolve_path` reads a path, so `/%61dmin` and `//admin/./x` are under
+/// `/admin`. Comparing the raw path instead would let a client put a
+/// protected file out of reach of the check but not of the file server.
+pub(crate) fn path_under_prefix(path: &str, prefix: &str) -> bool {
+    normalized_segments(path).starts_with(&normalized_segments(prefix))
+}
+
+/// Decodes `path`, keeping escapes that don't decode, and resolves its
+/// segments: empty and `.` ones are dropped and `..` removes the one
+/// before it.
+fn normalized_segments(path: &str) -> Vec<String> {
+    let mut segments = Vec::new();
+    for segment in percent_decode_lossy(path).split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment.to_string()),
+        }
+    }
+    segments
+}
+
+/// Answers with `404.html` from the document root, or with the built-in
+/// error page when that file can't be read.
+pub fn not_found(document_root: &Path, head_only: bool) -> Response {
+    let page = document_root.join("404.html");
+    file_response(&page, StatusCode::NOT_FOUND, head_only, None).unwrap_or_else(|err| {
+        if err.kind() != ErrorKind::NotFound {
+            log::warn!("Failed to read {}: {err}", page.display());
+        }
+        Response::error(StatusCode::NOT_FOUND)
+    })
+}
+
+/// Sends `/docs` on to `/docs/` so relative links in its index or listing
+/// resolve inside the directory.
+fn redirect_to_slash(path: &str, request: &Request) -> Response {
+    let location = match request.target.split_once('?') {
+        Some((_, query)) => format!("{path}/?{query}"),
+        None => format!("{path}/"),
+    };
+    Response::new(StatusCode::MOVED_PERMANENTLY).header("Location", location)
+}
+
+fn serve_directory(
+    dir: &Path,
+    request: &Request,
+    options: &ServeOptions,
+) -> Result<Response, ResolveError> {
+    let index = dir.join("index.html");
+    if index.is_file() {
+        return Ok(serve_file(&index, request, options.cache.as_deref())?);
+    }
+    if !options.directory_listing {
+        return Err(ResolveError::Forbidden);
+    }
+    Ok(Response::new(StatusCode::OK)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(directory_listing(dir, &request.path)?))
+}
+
+/// Renders the entries of `dir` as an HTML page of links, directories first.
+/// `request_path` is the still-encoded path the listing was requested under.
+fn directory_listing(dir: &Path, request_path: &str) -> io::Result<String> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        entries.push((!metadata.is_dir(), name, metadata.len()));
+    }
+    entries.sort();
+
+    let base = request_path.trim_end_matches('/');
+    let title = html_escape(&percent_decode(request_path).unwrap_or_default());
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>Index of {title}</title></head>\n\
+         <body>\n<h1>Index of {title}</h1>\n<ul>\n"
+    );
+    if !base.is_empty() {
+        html.push_str(&format!(
+            "<li><a href=\"{}/\">../</a></li>\n",
+            html_escape(base.rsplit_once('/').map_or("", |(parent, _)| parent))
+        ));
+    }
+    for (is_file, name, len) in entries {
+        let href = html_escape(&format!("{base}/{}", percent_encode(&name)));
+        let name = html_escape(&name);
+        if is_file {
+            html.push_str(&format!(
+                "<li><a href=\"{href}\">{name}</a> ({len} bytes)</li>\n"
+            ));
+        } else {
+            html.push_str(&format!("<li><a href=\"{href}/\">{name}/</a></li>\n"));
+        }
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    Ok(html)
+}
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Serves `fname` in full, or the byte range asked for by a `Range` header
+/// as long as any `If-Range` still matches.
+///
+/// Every response carries an `ETag` and `Last-Modified`, and all but the
+/// `304` advertise `Accept-Ranges: bytes`. A matching
+/// `If-None-Match`, or failing that an `If-Modified-Since` no older than the
+/// file, gets a bodiless `304 Not Modified`.
+fn serve_file(fname: &Path, request: &Request, cache: Option<&FileCache>) -> io::Result<Response> {
+    let head_only = request.method == Method::Head;
+    let metadata = fs::metadata(fname)?;
+    let len = metadata.len();
+    let etag = etag_for(&metadata);
+    let modified = metadata.modified().ok().map(truncate_to_secs);
+
+    let not_modified = match request.header("If-None-Match") {
+        Some(tags) => etag_matches(tags, &etag),
+        None => request
+            .header("If-Modified-Since")
+            .and_then(parse_http_date)
+            .zip(modified)
+            .is_some_and(|(since, modified)| modified <= since),
+    };
+    let with_validators = |response: Response| match modified {
+        Some(modified) => response
+            .header("ETag", &etag)
+            .header("Last-Modified", format_http_date(modified)),
+        None => response.header("ETag", &etag),
+    };
+    if not_modified {
+        return Ok(with_validators(Response::new(StatusCode::NOT_MODIFIED)));
+    }
+
+    // A resuming client sends `If-Range` with what it has; when the file has
+    // changed since, it gets all of it rather than a piece of a different
+    // version.
+    let range_valid =
+        request
+            .header("If-Range")
+            .is_none_or(|validator| match parse_http_date(validator) {
+                Some(date) => modified == Some(date),
+                None => validator == etag,
+            });
+    let response = match request
+        .header("Range")
+        .filter(|_| range_valid)
+        .and_then(|range| parse_range(range, len))
+    {
+        None => with_validators(file_response(fname, StatusCode::OK, head_only, cache)?),
+        Some(ByteRange::Unsatisfiable) => Response::new(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header("Content-Range", format!("bytes */{len}")),
+        Some(ByteRange::Satisfiable { start, end }) => {
+            let count = end - start + 1;
+            let response = Response::new(StatusCode::PARTIAL_CONTENT)
+                .header("Content-Type", content_type_for(fname))
+                .header("Content-Range", format!("bytes {start}-{end}/{len}"));
+            let response = if head_only {
+                response.header("Content-Length", count.to_string())
+            } else {
+                let mut file = File::open(fname)?;
+                file.seek(SeekFrom::Start(start))?;
+                response.stream(file, count)
+            };
+            with_validators(response)
+        }
+    };
+    Ok(response.header("Accept-Ranges", "bytes"))
+}
+
+/// A validator built from the file's size and modification time, so it can
+/// be computed without reading the contents.
+fn etag_for(metadata: &Metadata) -> String {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |mtime| mtime.as_nanos());
+    format!("\"{mtime:x}-{:x}\"", metadata.len())
+}
+
+/// HTTP dates have one-second resolution, so modification times are compared
+/// without their sub-second part.
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs()),
+        Err(_) => time,
+    }
+}
+
+/// Checks an `If-None-Match` list against `etag` using weak comparison,
+/// under which the tag of the file's gzipped form matches too.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || strip_gzip_etag(tag.trim_start_matches("W/")) == etag)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// Inclusive byte offsets, already clamped to the file length.
+    Satisfiable {
+        start: u64,
+        end: u64,
+    },
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range against a file of `len` bytes.
+///
+/// Returns `None` for anything the server should ignore and answer with the
+/// whole file instead: other units, malformed ranges, and multiple ranges,
+/// which aren't supported.
+fn parse_range(value: &str, len: u64) -> Option<ByteRange> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        return Some(ByteRange::Satisfiable {
+            start: len.saturating_sub(suffix),
+            end: len - 1,
+        });
+    }
+
+    let start: u64 = start.parse().ok()?;
+    let end = match end {
+        "" => None,
+        end => Some(end.parse::<u64>().ok()?),
+    };
+    if end.is_some_and(|end| end < start) {
+        return None;
+    }
+    if start >= len {
+        return Some(ByteRange::Unsatisfiable);
+    }
+    let end = end.map_or(len - 1, |end| end.min(len - 1));
+    Some(ByteRange::Satisfiable { start, end })
+}
+
+/// Files larger than this are streamed from disk rather than read into
+/// memory.
+const STREAM_THRESHOLD: u64 = 64 * 1024;
+
+/// Builds a response with `fname` as the body. With `head_only` set the
+/// length is taken from the file metadata and the body is left empty, as
+/// `HEAD` requires. Files small enough to be read in full come from `cache`
+/// when there is one.
+fn file_response(
+    fname: &Path,
+    status: StatusCode,
+    head_only: bool,
+    cache: Option<&FileCache>,
+) -> io::Result<Response> {
+    let response = Response::new(status).header("Content-Type", content_type_for(fname));
+    if head_only {
+        let len = fs::metadata(fname)?.len();
+        return Ok(response.header("Content-Length", len.to_string()));
+    }
+    let file = File::open(fname)?;
+    let len = file.metadata()?.len();
+    if len > STREAM_THRESHOLD {
+        return Ok(response.stream(file, len));
+    }
+    let body = match cache {
+        Some(cache) => cache.read(fname, &file.metadata()?)?.to_vec(),
+        None => fs::read(fname)?,
+    };
+    Ok(response
+        .header("Content-Length", body.len().to_string())
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use super::*;
+    use crate::test_support::{split_head, TempDir};
+
+    fn get(path: &str) -> Request {
+        let raw = format!("GET {path} HTTP/1.1\r\n\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn get_with(path: &str, name: &str, value: &str) -> Request {
+        let raw = format!("GET {path} HTTP/1.1\r\n{name}: {value}\r\n\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn paths_resolve_under_the_root() {
+        let dir = TempDir::new();
+        let outside = dir.write("secret.txt", "secret");
+        let file = dir.write("root/index.html", "<h1>hi</h1>");
+        let root = dir.path().join("root");
+
+        let resolved = resolve_path(&root, "/index.html").unwrap();
+        assert_eq!(resolved, file.canonicalize().unwrap());
+        assert!(outside.exists());
+        for path in ["/../secret.txt", "/%2e%2e/secret.txt", "/%2E%2E/secret.txt"] {
+            assert!(
+                matches!(resolve_path(&root, path), Err(ResolveError::Forbidden)),
+                "{path}"
+            );
+            let response = serve_path(&root, path, &get(path));
+            assert_eq!(response.status, StatusCode::FORBIDDEN, "{path}");
+        }
+    }
+
+    #[test]
+    fn encoded_paths_are_decoded_before_lookup() {
+        let root = TempDir::new();
+        let file = root.write("my file.html", "spaced");
+        let resolved = resolve_path(root.path(), "/my%20file.html").unwrap();
+        assert_eq!(resolved, file.canonicalize().unwrap());
+        assert!(matches!(
+            resolve_path(root.path(), "/my%ZZfile.html"),
+            Err(ResolveError::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    fn binary_files_are_served_byte_for_byte() {
+        let root = TempDir::new();
+        let blob = [0x89, b'P', b'N', b'G', 0xFF, 0xFE, 0x00, 0x01];
+        root.write("blob.png", blob);
+
+        let mut response = serve_path(root.path(), "/blob.png", &get("/blob.png"));
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let (head, body) = split_head(&out);
+        assert!(head.contains("Content-Length: 8\r\n"), "{head}");
+        assert_eq!(body, blob);
+    }
+
+    #[test]
+    fn content_type_follows_the_extension() {
+        for (name, expected) in [
+            ("index.html", "text/html; charset=utf-8"),
+            ("style.css", "text/css; charset=utf-8"),
+            ("app.js", "text/javascript; charset=utf-8"),
+            ("data.json", "application/json"),
+            ("logo.png", "image/png"),
+            ("photo.JPG", "image/jpeg"),
+            ("icon.svg", "image/svg+xml"),
+            ("notes.txt", "text/plain; charset=utf-8"),
+            ("favicon.ico", "image/x-icon"),
+            ("archive.tar.gz", "application/octet-stream"),
+            ("README", "application/octet-stream"),
+        ] {
+            assert_eq!(content_type_for(Path::new(name)), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn missing_file_is_not_found() {
+        let root = TempDir::new();
+        let response = serve_path(root.path(), "/missing.html", &get("/missing.html"));
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn builtin_404_page_stands_in_for_a_missing_404_html() {
+        let root = TempDir::new();
+        let mut response = not_found(root.path(), false);
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let (head, body) = split_head(&out);
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"), "{head}");
+        assert!(head.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        let body = std::str::from_utf8(body).unwrap();
+        assert!(body.contains("<h1>404 Not Found</h1>"), "{body}");
+
+        root.write("404.html", "custom");
+        assert_eq!(not_found(root.path(), false).body, b"custom");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_file_is_an_internal_error() {
+        let root = TempDir::new();
+        // A symlink to itself can't be opened, even by root.
+        std::os::unix::fs::symlink("loop.html", root.path().join("loop.html")).unwrap();
+        let response = serve_path(root.path(), "/loop.html", &get("/loop.html"));
+        assert_eq!(response.status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    /// Serves `/a.txt` with the `name` header set to `value`, returning the
+    /// response along with its body as sent.
+    fn fetch(root: &TempDir, name: &str, value: &str) -> (Response, Vec<u8>) {
+        let mut response = serve_path(root.path(), "/a.txt", &get_with("/a.txt", name, value));
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let body = split_head(&out).1.to_vec();
+        (response, body)
+    }
+
+    #[test]
+    fn range_requests_get_partial_content() {
+        let root = TempDir::new();
+        root.write("a.txt", "0123456789");
+
+        let (response, body) = fetch(&root, "Range", "bytes=2-5");
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.header_value("Content-Range"), Some("bytes 2-5/10"));
+        assert_eq!(response.header_value("Content-Length"), Some("4"));
+        assert_eq!(body, b"2345");
+
+        let (response, body) = fetch(&root, "Range", "bytes=7-");
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.header_value("Content-Range"), Some("bytes 7-9/10"));
+        assert_eq!(body, b"789");
+
+        let (response, _) = fetch(&root, "Range", "bytes=20-30");
+        assert_eq!(response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.header_value("Content-Range"), Some("bytes */10"));
+
+        let (response, body) = fetch(&root, "Accept", "*/*");
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(body, b"0123456789");
+    }
+
+    #[test]
+    fn matching_etag_is_not_modified() {
+        let root = TempDir::new();
+        root.write("a.txt", "0123456789");
+        let (response, _) = fetch(&root, "Accept", "*/*");
+        let etag = response.header_value("ETag").unwrap().to_string();
+
+        let (response, body) = fetch(&root, "If-None-Match", &etag);
+        assert_eq!(response.status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+        assert_eq!(response.header_value("ETag"), Some(etag.as_str()));
+
+        let (response, _) = fetch(&root, "If-None-Match", "\"other\", *");
+        assert_eq!(response.status, StatusCode::NOT_MODIFIED);
+        let (response, _) = fetch(&root, "If-None-Match", "\"other\"");
+        assert_eq!(response.status, StatusCode::OK);
+    }
+
+    #[test]
+    fn if_modified_since_compares_whole_seconds() {
+        let root = TempDir::new();
+        root.write("a.txt", "0123456789");
+        let (response, _) = fetch(&root, "Accept", "*/*");
+        let last_modified = response.header_value("Last-Modified").unwrap().to_string();
+
+        let (response, body) = fetch(&root, "If-Modified-Since", &last_modified);
+        assert_eq!(response.status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+
+        let older =
+            format_http_date(parse_http_date(&last_modified).unwrap() - Duration::from_secs(60));
+        let (response, _) = fetch(&root, "If-Modified-Since", &older);
+        assert_eq!(response.status, StatusCode::OK);
+
+        let (response, _) = fetch(&root, "If-Modified-Since", "yesterday");
+        assert_eq!(response.status, StatusCode::OK);
+    }
+
+    fn listing_options() -> ServeOptions {
+        ServeOptions {
+            directory_listing: true,
+            ..ServeOptions::default()
+        }
+    }
+
+    #[test]
+    fn directory_listing_shows_escaped_entries() {
+        let root = TempDir::new();
+        root.write("assets/app.js", "console.log(1)");
+        root.write("assets/<b>.txt", "bold");
+        let request = get("/assets/");
+
+        let response =
+            serve_path_with_options(root.path(), "/assets/", &request, &listing_options());
+        assert_eq!(response.status, StatusCode::OK);
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(
+            body.contains("<a href=\"/assets/app.js\">app.js</a> (14 bytes)"),
+            "{body}"
+        );
+        assert!(
+            body.contains("<a href=\"/assets/%3Cb%3E.txt\">&lt;b&gt;.txt</a>"),
+            "{body}"
+        );
+        assert!(!body.contains("<b>"), "{body}");
+
+        let response = serve_path(root.path(), "/assets/", &request);
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn directory_index_beats_the_listing() {
+        let root = TempDir::new();
+        root.write("assets/index.html", "index");
+        let request = get("/assets/");
+        let response =
+            serve_path_with_options(root.path(), "/assets/", &request, &listing_options());
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body, b"index");
+    }
+
+    #[test]
+    fn directories_serve_their_index() {
+        let root = TempDir::new();
+        root.write("docs/index.html", "<h1>docs</h1>");
+
+        let response = serve_path(root.path(), "/docs/", &get("/docs/"));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body, b"<h1>docs</h1>");
+        assert_eq!(
+            response.header_value("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        let response = serve_path(root.path(), "/docs", &get("/docs?page=2"));
+        assert_eq!(response.status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.header_value("Location"), Some("/docs/?page=2"));
+    }
+
+    /// Collects what's written and remembers the largest single write.
+    #[derive(Default)]
+    struct RecordingWriter {
+        bytes: Vec<u8>,
+        largest_write: usize,
+    }
+
+    impl Write for RecordingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.largest_write = self.largest_write.max(buf.len());
+            self.bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn large_file_is_streamed_in_small_pieces() {
+        let root = TempDir::new();
+        let contents: Vec<u8> = (0..2 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        root.write("large.bin", &contents);
+
+        let mut response = serve_path(root.path(), "/large.bin", &get("/large.bin"));
+        assert!(response.is_streamed());
+        assert!(response.body.is_empty());
+        let mut writer = RecordingWriter::default();
+        assert_eq!(
+            response.write_to(&mut writer).unwrap(),
+            contents.len() as u64
+        );
+
+        let (head, body) = split_head(&writer.bytes);
+        assert!(head.contains(&format!("Content-Length: {}\r\n", contents.len())));
+        assert!(body == contents, "streamed body differs from the file");
+        assert!(
+            writer.largest_write <= 64 * 1024,
+            "wrote {} bytes at once",
+            writer.largest_write
+        );
+    }
+
+    #[test]
+    fn gzipped_etag_revalidates_but_does_not_match_ranges() {
+        let root = TempDir::new();
+        root.write("a.txt", "0123456789");
+        let etag = serve_path(root.path(), "/a.txt", &get("/a.txt"))
+            .header_value("ETag")
+            .unwrap()
+            .to_string();
+        let gzip_tag = format!("{}-gzip\"", etag.trim_end_matches('"'));
+
+        let request = get_with("/a.txt", "If-None-Match", &gzip_tag);
+        let response = serve_path(root.path(), "/a.txt", &request);
+        assert_eq!(response.status, StatusCode::NOT_MODIFIED);
+
+        let raw =
+            format!("GET /a.txt HTTP/1.1\r\nRange: bytes=0-3\r\nIf-Range: {gzip_tag}\r\n\r\n");
+        let request = Request::parse(&mut raw.as_bytes()).unwrap();
+        let response = serve_path(root.path(), "/a.txt", &request);
+        assert_eq!(response.status, StatusCode::OK);
+    }
+
+    #[test]
+    fn spa_fallback_serves_the_index_for_app_routes() {
+        let root = TempDir::new();
+        root.write("index.html", "<div id=app></div>");
+        root.write("app.js", "boot()");
+        let options = ServeOptions {
+            spa_fallback: true,
+            spa_api_prefixes: vec!["/api".to_string()],
+            ..ServeOptions::default()
+        };
+        let serve = |path: &str, options: &ServeOptions| {
+            let mut response = serve_path_with_options(root.path(), path, &get(path), options);
+            let mut out = Vec::new();
+            response.write_to(&mut out).unwrap();
+            (response.status, split_head(&out).1.to_vec())
+        };
+
+        assert_eq!(
+            serve("/some/app/route", &options),
+            (StatusCode::OK, b"<div id=app></div>".to_vec())
+        );
+        assert_eq!(
+            serve("/app.js", &options),
+            (StatusCode::OK, b"boot()".to_vec())
+        );
+        assert_eq!(serve("/missing.js", &options).0, StatusCode::NOT_FOUND);
+        assert_eq!(serve("/api/users", &options).0, StatusCode::NOT_FOUND);
+        assert_eq!(serve("//%61pi/users", &options).0, StatusCode::NOT_FOUND);
+        assert_eq!(
+            serve("/some/app/route", &ServeOptions::default()).0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn files_advertise_byte_ranges_to_get_and_head() {
+        let root = TempDir::new();
+        root.write("a.txt", "0123456789");
+
+        let (response, body) = fetch(&root, "Accept", "*/*");
+        assert_eq!(response.header_value("Accept-Ranges"), Some("bytes"));
+        assert_eq!(body, b"0123456789");
+
+        let raw = b"HEAD /a.txt HTTP/1.1\r\n\r\n";
+        let head = Request::parse(&mut &raw[..]).unwrap();
+        let response = serve_path(root.path(), "/a.txt", &head);
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.header_value("Accept-Ranges"), Some("bytes"));
+        assert_eq!(response.header_value("Content-Length"), Some("10"));
+        assert!(response.body.is_empty());
+        assert!(!response.is_streamed());
+    }
+
+    #[test]
+    fn if_range_gets_the_range_only_while_the_validator_matches() {
+        let root = TempDir::new();
+        root.write("a.txt", "0123456789");
+        let (full, _) = fetch(&root, "Accept", "*/*");
+        let etag = full.header_value("ETag").unwrap().to_string();
+        let last_modified = full.header_value("Last-Modified").unwrap().to_string();
+        let resume = |validator: &str| {
+            let raw =
+                format!("GET /a.txt HTTP/1.1\r\nRange: bytes=4-\r\nIf-Range: {validator}\r\n\r\n");
+            let request = Request::parse(&mut raw.as_bytes()).unwrap();
+            let mut response = serve_path(root.path(), "/a.txt", &request);
+            let mut out = Vec::new();
+            response.write_to(&mut out).unwrap();
+            (response.status, split_head(&out).1.to_vec())
+        };
+
+        assert_eq!(
+            resume(&etag),
+            (StatusCode::PARTIAL_CONTENT, b"456789".to_vec())
+        );
+        assert_eq!(
+            resume(&last_modified),
+            (StatusCode::PARTIAL_CONTENT, b"456789".to_vec())
+        );
+        assert_eq!(
+            resume("\"stale\""),
+            (StatusCode::OK, b"0123456789".to_vec())
+        );
+        assert_eq!(
+            resume("Sun, 06 Nov 1994 08:49:37 GMT"),
+            (StatusCode::OK, b"0123456789".to_vec())
+        );
+    }
+}
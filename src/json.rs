@@ -0,0 +1,132 @@
+//! JSON bodies through serde, behind the `json` feature.
+
+use std::fmt;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{log, request::Request, response::Response, status::StatusCode};
+
+/// Why `Request::json` couldn't parse the body.
+#[derive(Debug)]
+pub enum JsonError {
+    EmptyBody,
+    /// The body isn't JSON, or doesn't have the expected shape.
+    Invalid(serde_json::Error),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::EmptyBody => write!(f, "request body is empty"),
+            JsonError::Invalid(err) => write!(f, "invalid JSON body: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JsonError::EmptyBody => None,
+            JsonError::Invalid(err) => Some(err),
+        }
+    }
+}
+
+/// A `400 Bad Request` saying what was wrong, as `{"error": "..."}`, so a
+/// route can answer a bad body with the error itself.
+impl From<JsonError> for Response {
+    fn from(err: JsonError) -> Self {
+        Response::json(
+            StatusCode::BAD_REQUEST,
+            &serde_json::json!({ "error": err.to_string() }),
+        )
+    }
+}
+
+impl Response {
+    /// A response with `value` serialized as its body. Values serde can't
+    /// represent as JSON, such as maps with non-string keys, give a `500`.
+    pub fn json<T: Serialize + ?Sized>(status: StatusCode, value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response::new(status)
+                .header("Content-Type", "application/json")
+                .body(body),
+            Err(err) => {
+                log::error!("Failed to serialize JSON response: {err}");
+                Response::error(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+impl Request {
+    /// Parses the body as JSON. The `Content-Type` isn't checked.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+        if self.body.is_empty() {
+            return Err(JsonError::EmptyBody);
+        }
+        serde_json::from_slice(&self.body).map_err(JsonError::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn post(body: &str) -> Request {
+        let raw = format!(
+            "POST /items HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn values_round_trip_through_a_response_and_a_request() {
+        let item = BTreeMap::from([("name", "widget"), ("color", "blue")]);
+        let response = Response::json(StatusCode::CREATED, &item);
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert_eq!(
+            response.header_value("Content-Type"),
+            Some("application/json")
+        );
+
+        let body = String::from_utf8(response.body).unwrap();
+        assert_eq!(body, r#"{"color":"blue","name":"widget"}"#);
+        let parsed: BTreeMap<String, String> = post(&body).json().unwrap();
+        assert_eq!(parsed["name"], "widget");
+        assert_eq!(parsed.len(), 2);
+    }
+
+    #[test]
+    fn empty_and_malformed_bodies_are_errors() {
+        assert!(matches!(
+            post("").json::<Vec<u32>>(),
+            Err(JsonError::EmptyBody)
+        ));
+        assert!(matches!(
+            post("[1, 2").json::<Vec<u32>>(),
+            Err(JsonError::Invalid(_))
+        ));
+        assert!(matches!(
+            post(r#"["one"]"#).json::<Vec<u32>>(),
+            Err(JsonError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn errors_become_bad_requests_that_say_why() {
+        let response = Response::from(JsonError::EmptyBody);
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.body, br#"{"error":"request body is empty"}"#);
+    }
+
+    #[test]
+    fn unrepresentable_values_are_internal_errors() {
+        let map = BTreeMap::from([((1, 2), "tuple keys")]);
+        let response = Response::json(StatusCode::OK, &map);
+        assert_eq!(response.status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,60 @@
+mod access_log;
+mod auth;
+mod base64;
+mod compress;
+mod cors;
+mod file_cache;
+mod files;
+mod httpdate;
+#[cfg(feature = "json")]
+mod json;
+mod listener;
+mod log;
+mod metrics;
+mod percent;
+mod pool;
+mod proxy;
+mod rate_limit;
+mod request;
+mod request_id;
+mod response;
+mod router;
+mod server;
+mod sha1;
+mod sse;
+mod status;
+#[cfg(test)]
+mod test_support;
+#[cfg(feature = "tls")]
+mod tls;
+mod websocket;
+
+pub use access_log::{AccessLog, AccessLogger};
+pub use auth::BasicAuth;
+pub use cors::Cors;
+pub use file_cache::FileCache;
+pub use files::{
+    content_type_for, resolve_path, serve_path, serve_path_with_options, NotFoundHandler,
+    ResolveError, ServeOptions,
+};
+pub use httpdate::{format_http_date, parse_http_date};
+#[cfg(feature = "json")]
+pub use json::JsonError;
+pub use log::Level as LogLevel;
+pub use metrics::Metrics;
+pub use percent::{percent_decode, percent_encode, DecodeError};
+pub use pool::{ExecuteError, JoinError, PoolError, QueueStats, ThreadPool};
+pub use proxy::ProxyHandler;
+pub use rate_limit::RateLimit;
+pub use request::{Method, ParseError, ParseLimits, Request};
+pub use response::Response;
+pub use router::{Handler, Middleware, Router};
+pub use server::{
+    default_router, run_server, run_server_with_router, ConnectionError, ReloadError, Server,
+    ServerConfig, ServerHandle,
+};
+pub use sse::SseStream;
+pub use status::StatusCode;
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
+pub use websocket::{accept_websocket, websocket_accept_key, Upgraded};
@@ -0,0 +1,429 @@
+//! The sockets the server accepts connections on, TCP or Unix domain.
+
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::log;
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+
+/// Binds a TCP listener on `addr`.
+///
+/// IPv6 addresses are bound with `IPV6_V6ONLY` turned off where the platform
+/// allows it, so `[::]` accepts IPv4 clients too. When IPv6 isn't available
+/// at all, `[::]` falls back to `0.0.0.0` on the same port.
+///
+/// Errors name the address, keeping the original kind, so a port conflict
+/// reads as `failed to bind 0.0.0.0:7878: Address already in use`.
+pub(crate) fn bind_tcp(addr: SocketAddr, options: &ListenOptions) -> io::Result<Listener> {
+    bind_tcp_listener(addr, options)
+        .map(Listener::Tcp)
+        .map_err(|err| io::Error::new(err.kind(), format!("failed to bind {addr}: {err}")))
+}
+
+/// Socket options applied before listening.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ListenOptions {
+    pub(crate) backlog: u32,
+    /// Only honoured on Unix, where it matches `TcpListener::bind`. On
+    /// Windows `SO_REUSEADDR` would let another process steal the port.
+    pub(crate) reuse_address: bool,
+}
+
+fn bind_tcp_listener(addr: SocketAddr, options: &ListenOptions) -> io::Result<TcpListener> {
+    match bind_socket(addr, options) {
+        Err(err) if addr.is_ipv6() && addr.ip().is_unspecified() && ipv6_unavailable(&err) => {
+            log::warn!("IPv6 unavailable ({err}), listening on IPv4 only");
+            bind_socket(
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, addr.port())),
+                options,
+            )
+        }
+        result => result,
+    }
+}
+
+fn bind_socket(addr: SocketAddr, options: &ListenOptions) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Lets a restarted server rebind a port whose old connections are
+    // still in TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(options.reuse_address)?;
+    if addr.is_ipv6() {
+        if let Err(err) = socket.set_only_v6(false) {
+            log::debug!("Could not enable dual-stack listening: {err}");
+        }
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(i32::try_from(options.backlog).unwrap_or(i32::MAX))?;
+    Ok(socket.into())
+}
+
+/// Errors that mean the port itself can't be used, which falling back to
+/// IPv4 wouldn't fix, aren't treated as IPv6 being unavailable.
+fn ipv6_unavailable(err: &io::Error) -> bool {
+    !matches!(
+        err.kind(),
+        ErrorKind::AddrInUse | ErrorKind::PermissionDenied
+    )
+}
+
+/// A bound socket of either kind, accepting `Stream`s.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        path: PathBuf,
+    },
+}
+
+impl Listener {
+    /// Binds a Unix domain socket at `path`, first removing a socket file
+    /// left behind by a previous run.
+    #[cfg(unix)]
+    pub(crate) fn bind_unix(path: &Path) -> io::Result<Listener> {
+        use std::os::unix::fs::FileTypeExt;
+
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                log::debug!("Removing stale socket {}", path.display());
+                fs::remove_file(path)?;
+            }
+            _ => {}
+        }
+        let listener = UnixListener::bind(path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("failed to bind {}: {err}", path.display()),
+            )
+        })?;
+        Ok(Listener::Unix {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr(),
+            #[cfg(unix)]
+            Listener::Unix { .. } => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "listening on a Unix socket",
+            )),
+        }
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub(crate) fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => Ok(Stream::Tcp(listener.accept()?.0)),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => Ok(Stream::Unix(listener.accept()?.0)),
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => write!(f, "an unknown address"),
+            },
+            #[cfg(unix)]
+            Listener::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix { path, .. } = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// What `handle_connection` needs from a connection: reading and writing,
+/// plus read timeouts where the transport has them. In-memory streams can
+/// rely on the default, which ignores timeouts.
+pub(crate) trait Connection: Read + Write + Send + 'static {
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for Stream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Stream::set_read_timeout(self, timeout)
+    }
+}
+
+/// A connection held in memory: reads come from a fixed input and writes
+/// are collected where the test can still see them once the connection
+/// has been dropped.
+#[cfg(test)]
+pub(crate) struct MemoryConnection {
+    input: io::Cursor<Vec<u8>>,
+    output: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl MemoryConnection {
+    /// Returns the connection and a handle on everything written to it.
+    pub(crate) fn new(
+        input: impl Into<Vec<u8>>,
+    ) -> (MemoryConnection, std::sync::Arc<std::sync::Mutex<Vec<u8>>>) {
+        let output = std::sync::Arc::default();
+        let connection = MemoryConnection {
+            input: io::Cursor::new(input.into()),
+            output: std::sync::Arc::clone(&output),
+        };
+        (connection, output)
+    }
+}
+
+#[cfg(test)]
+impl Read for MemoryConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl Write for MemoryConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Connection for MemoryConnection {}
+
+/// An accepted connection. Like `TcpStream`, it can be read and written
+/// through a shared reference.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl Stream {
+    /// The client's address, or `None` for Unix socket peers.
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.sock().peer_addr().ok(),
+        }
+    }
+
+    /// Wraps a TCP connection in TLS; other streams are returned as they are.
+    #[cfg(feature = "tls")]
+    pub(crate) fn into_tls(
+        self,
+        config: &std::sync::Arc<rustls::ServerConfig>,
+    ) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => Ok(Stream::Tls(Box::new(TlsStream::new(config, stream)?))),
+            stream => Ok(stream),
+        }
+    }
+
+    /// Completes the TLS handshake; a no-op for plain streams.
+    pub(crate) fn handshake(&self) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.handshake(),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.sock().set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.sock().set_write_timeout(timeout),
+        }
+    }
+
+    /// Closes the sending half, so the peer sees the response end while
+    /// what it sent can still be read.
+    pub(crate) fn shutdown_write(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Write),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Write),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.sock().shutdown(Shutdown::Write),
+        }
+    }
+
+    /// Sets `TCP_NODELAY`; a no-op for Unix sockets, which have no Nagle delay.
+    pub(crate) fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nodelay(nodelay),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(()),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.sock().set_nodelay(nodelay),
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    const OPTIONS: ListenOptions = ListenOptions {
+        backlog: 16,
+        reuse_address: true,
+    };
+
+    /// Connects to `addr`, has `listener` accept and echoes a byte back.
+    fn round_trip(listener: &Listener, addr: SocketAddr) {
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut accepted = listener.accept().unwrap();
+        client.write_all(b"x").unwrap();
+        let mut byte = [0];
+        accepted.read_exact(&mut byte).unwrap();
+        accepted.write_all(&byte).unwrap();
+        client.read_exact(&mut byte).unwrap();
+        assert_eq!(&byte, b"x");
+    }
+
+    #[test]
+    fn binds_and_accepts_over_ipv6() {
+        let listener = bind_tcp(SocketAddr::from((Ipv6Addr::LOCALHOST, 0)), &OPTIONS).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.is_ipv6(), "{addr}");
+        round_trip(&listener, addr);
+    }
+
+    #[test]
+    fn unspecified_ipv6_accepts_ipv4_clients_too() {
+        let listener = bind_tcp(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)), &OPTIONS).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        round_trip(&listener, SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reuse_address_rebinds_a_port_in_time_wait() {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let listener = bind_tcp(addr, &OPTIONS).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).unwrap();
+        // Closing the server's end first leaves it in TIME_WAIT.
+        drop(listener.accept().unwrap());
+        drop(client);
+        drop(listener);
+
+        let listener = bind_tcp(addr, &OPTIONS).unwrap();
+        round_trip(&listener, addr);
+    }
+
+    #[test]
+    fn port_with_a_listener_is_still_in_use() {
+        let listener = bind_tcp(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), &OPTIONS).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let err = bind_tcp(addr, &OPTIONS).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        assert!(err.to_string().contains(&addr.to_string()), "{err}");
+    }
+}
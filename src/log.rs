@@ -0,0 +1,205 @@
+//! A minimal leveled logger writing to stderr.
+//!
+//! The maximum level is process-wide. It defaults to `info` and can be set
+//! through the `RUST_LOG` environment variable or `ServerConfig::log_level`.
+
+use std::{
+    env, fmt,
+    io::{self, Write},
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    fn from_u8(level: u8) -> Level {
+        match level {
+            0 => Level::Off,
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level.to_ascii_lowercase().as_str() {
+            "off" => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("unknown log level: {level}")),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn max_level() -> Level {
+    Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Sets the maximum level from `level`, or from `RUST_LOG` when it's `None`.
+/// An unset or unrecognized `RUST_LOG` leaves the current level in place.
+pub fn init(level: Option<Level>) {
+    let level = level.or_else(|| env::var("RUST_LOG").ok()?.parse().ok());
+    if let Some(level) = level {
+        set_max_level(level);
+    }
+}
+
+pub fn enabled(level: Level) -> bool {
+    level != Level::Off && level <= max_level()
+}
+
+#[doc(hidden)]
+pub fn log(level: Level, args: fmt::Arguments<'_>) {
+    let _ = writeln!(io::stderr().lock(), "[{level}] {args}");
+}
+
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::log($level, format_args!($($arg)+));
+        }
+    };
+}
+
+macro_rules! log_error {
+    ($($arg:tt)+) => { $crate::log::log_at!($crate::log::Level::Error, $($arg)+) };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)+) => { $crate::log::log_at!($crate::log::Level::Warn, $($arg)+) };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)+) => { $crate::log::log_at!($crate::log::Level::Info, $($arg)+) };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)+) => { $crate::log::log_at!($crate::log::Level::Debug, $($arg)+) };
+}
+
+// Defined under prefixed names because `warn` clashes with the built-in
+// attribute; call sites use them as `log::info!` and so on.
+pub(crate) use {
+    log_at, log_debug as debug, log_error as error, log_info as info, log_warn as warn,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHILD: &str = "WEB_SERVER_LOG_TEST_LEVEL";
+
+    /// Serves one request from a copy of this test binary that only runs
+    /// this test, logging at `level`, and returns what it wrote to stderr.
+    fn server_log(level: &str) -> String {
+        use std::process::Command;
+
+        let output = Command::new(env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "log::tests::error_level_suppresses_accept_messages",
+            ])
+            .env(CHILD, level)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", output.status);
+        String::from_utf8(output.stderr).unwrap()
+    }
+
+    #[test]
+    fn only_levels_up_to_the_maximum_are_enabled() {
+        // The other tests run at `error` too, so this doesn't disturb them.
+        init(Some(Level::Error));
+        assert!(enabled(Level::Error));
+        assert!(!enabled(Level::Warn));
+        assert!(!enabled(Level::Info));
+        assert!(!enabled(Level::Debug));
+        assert!(!enabled(Level::Off));
+    }
+
+    #[test]
+    fn error_level_suppresses_accept_messages() {
+        use std::{
+            io::{Read, Write},
+            net::{SocketAddr, TcpStream},
+        };
+
+        use crate::{server::Server, test_support::TempDir, AccessLog, ServerConfig};
+
+        if let Some(level) = env::var_os(CHILD) {
+            let root = TempDir::new();
+            root.write("index.html", "hi");
+            let config = ServerConfig {
+                addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+                document_root: root.path().to_path_buf(),
+                access_log: AccessLog::Disabled,
+                log_level: Some(level.to_str().unwrap().parse().unwrap()),
+                ..ServerConfig::default()
+            };
+            let handle = Server::bind(config).unwrap().run();
+            let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            client.read_to_end(&mut Vec::new()).unwrap();
+            handle.shutdown();
+            return;
+        }
+
+        let quiet = server_log("error");
+        assert!(!quiet.contains("Incoming connection accepted"), "{quiet}");
+        let verbose = server_log("debug");
+        assert!(
+            verbose.contains("Incoming connection accepted"),
+            "{verbose}"
+        );
+    }
+
+    #[test]
+    fn levels_parse_case_insensitively() {
+        assert_eq!("WARN".parse(), Ok(Level::Warn));
+        assert_eq!("debug".parse(), Ok(Level::Debug));
+        assert!("loud".parse::<Level>().is_err());
+    }
+}
@@ -1,151 +1,105 @@
-use std::{
-    fs,
-    io::{BufRead, BufReader, ErrorKind, Write},
-    marker::Send,
-    net::{TcpListener, TcpStream},
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        mpsc, Arc, Mutex,
-    },
-    thread::{self, JoinHandle},
-    time::Duration,
-};
+use std::{env, path::PathBuf, process};
 
-struct Worker {
-    thread: Option<JoinHandle<()>>,
-}
+use web_server::{run_server, ServerConfig};
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+#[cfg(not(feature = "tls"))]
+const USAGE: &str =
+    "Usage: web-server [--port <port>] [--socket <path>] [--threads <count>] [--root <dir>]";
+#[cfg(feature = "tls")]
+const USAGE: &str = "Usage: web-server [--port <port>] [--socket <path>] [--threads <count>] \
+                     [--root <dir>] [--tls-cert <path> --tls-key <path>]";
 
-impl Worker {
-    pub fn new(id: u32, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        Worker {
-            thread: Some(thread::spawn(move || loop {
-                let f = receiver.lock().unwrap().recv();
-                match f {
-                    Ok(f) => {
-                        println!("Got a new task worker_id:{id}");
-                        f();
-                    }
-                    Err(_) => {
-                        println!("Worker {id} disconnected, exiting...");
-                        break;
-                    }
-                }
-            })),
+fn main() {
+    let config = match parse_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            eprintln!("{USAGE}");
+            process::exit(2);
         }
+    };
+    if let Err(err) = run_server(config) {
+        eprintln!("Failed to run server: {err}");
+        process::exit(1);
     }
 }
 
-struct ThreadPool {
-    tx: Option<mpsc::Sender<Job>>,
-    workers: Vec<Worker>,
-}
-
-impl ThreadPool {
-    pub fn new(size: usize) -> Self {
-        assert!(size > 0, "size <= 0");
-        let (tx, rx) = mpsc::channel();
-        let rx = Arc::new(Mutex::new(rx));
-        let mut workers = Vec::with_capacity(size);
-        for i in 0..size {
-            let id: u32 = (i + 1).try_into().unwrap();
-            workers.push(Worker::new(id, rx.clone()))
-        }
-
-        ThreadPool {
-            tx: Some(tx),
-            workers,
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<ServerConfig, String> {
+    let mut config = ServerConfig::default();
+    let mut args = args.into_iter();
+    #[cfg(feature = "tls")]
+    let (mut tls_cert, mut tls_key) = (None, None);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--port" => {
+                let port = value()?;
+                let port = port.parse().map_err(|_| format!("Invalid port: {port}"))?;
+                config.addr.set_port(port);
+            }
+            "--threads" => {
+                let threads = value()?;
+                config.pool_size = match threads.parse() {
+                    Ok(0) | Err(_) => return Err(format!("Invalid thread count: {threads}")),
+                    Ok(threads) => threads,
+                };
+            }
+            #[cfg(unix)]
+            "--socket" => config.unix_socket = Some(PathBuf::from(value()?)),
+            "--root" => config.document_root = PathBuf::from(value()?),
+            #[cfg(feature = "tls")]
+            "--tls-cert" => tls_cert = Some(PathBuf::from(value()?)),
+            #[cfg(feature = "tls")]
+            "--tls-key" => tls_key = Some(PathBuf::from(value()?)),
+            _ => return Err(format!("Unknown argument: {arg}")),
         }
     }
-
-    fn run<T>(&mut self, job: T) -> Result<(), &str>
-    where
-        T: FnOnce() + Send + 'static,
-    {
-        if let Some(tx) = &self.tx {
-            tx.send(Box::new(job)).unwrap();
-            return Ok(());
-        } else {
-            return Err("sender already dropped");
+    #[cfg(feature = "tls")]
+    match (tls_cert, tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            config.tls = Some(web_server::TlsConfig {
+                cert_path,
+                key_path,
+            });
         }
+        (None, None) => {}
+        _ => return Err("--tls-cert and --tls-key must be given together".to_string()),
     }
+    Ok(config)
 }
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        let tx = self.tx.take();
-        drop(tx);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        for worker in &mut self.workers {
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            }
-        }
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
     }
-}
 
-fn main() {
-    let tcp_listener = TcpListener::bind("0.0.0.0:7878").unwrap();
-    println!("Server started");
-    let mut pool = ThreadPool::new(10);
-    let should_exit = Arc::new(AtomicBool::new(false));
-
-    {
-        let should_exit = should_exit.clone();
-        ctrlc::set_handler(move || should_exit.store(true, Ordering::SeqCst))
-            .expect("Failed to set Ctrl+C handler");
+    #[test]
+    fn parses_port_and_threads() {
+        let config = parse_args(args(&["--port", "9000", "--threads", "2"])).unwrap();
+        assert_eq!(config.addr.port(), 9000);
+        assert_eq!(config.pool_size, 2);
+        assert_eq!(config.document_root, ServerConfig::default().document_root);
     }
 
-    tcp_listener
-        .set_nonblocking(true)
-        .expect("Failed to set non-blocking");
-
-    for stream in tcp_listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                println!("Incoming connection accepted");
-                pool.run(move || handle_connection(stream)).unwrap();
-            }
-            Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                if should_exit.load(Ordering::SeqCst) {
-                    println!("Ctrl+C pressed, exiting...");
-                    break;
-                }
-                thread::yield_now();
-                // loop
-            }
-            Err(err) => {
-                println!("Failed connection {err}");
-            }
-        }
+    #[test]
+    fn omitted_flags_keep_the_defaults() {
+        let config = parse_args(args(&[])).unwrap();
+        let default = ServerConfig::default();
+        assert_eq!(config.addr, default.addr);
+        assert_eq!(config.pool_size, default.pool_size);
     }
-}
 
-fn handle_connection(mut stream: TcpStream) {
-    let reader = BufReader::new(&mut stream);
-    match reader.lines().next().unwrap() {
-        Ok(request) => {
-            let (file, status) = match &request[..] {
-                "GET / HTTP/1.1" => ("index.html", "200 OK"),
-                "GET /sleep HTTP/1.1" => {
-                    thread::sleep(Duration::from_secs(5));
-                    ("sleep.html", "200 OK")
-                }
-                _ => ("404.html", "404 NOT FOUND"),
-            };
-            response_file(&mut stream, file, status);
-        }
-        Err(err) => {
-            println!("Handle connection error: {err}");
-        }
+    #[test]
+    fn invalid_values_are_errors() {
+        assert_eq!(
+            parse_args(args(&["--port", "abc"])).err().unwrap(),
+            "Invalid port: abc"
+        );
+        assert!(parse_args(args(&["--threads", "0"])).is_err());
+        assert!(parse_args(args(&["--port"])).is_err());
+        assert!(parse_args(args(&["--verbose"])).is_err());
     }
 }
-
-fn response_file(stream: &mut TcpStream, fname: &str, status: &str) {
-    let html = fs::read_to_string(fname).unwrap();
-    let status_line = format!("HTTP/1.1 {status}");
-    let content_len_header = format!("Content-Length: {}", html.len());
-    let response = format!("{status_line}\r\n{content_len_header}\r\n\r\n{html}\r\n\r\n");
-    stream.write_all(response.as_bytes()).unwrap();
-}
@@ -0,0 +1,178 @@
+//! Server-wide counters, rendered on `/metrics` in the Prometheus text
+//! exposition format.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{pool::QueueStats, status::StatusCode};
+
+const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests_total: AtomicU64,
+    /// Responses sent, indexed by status class: `1xx` first, `5xx` last.
+    responses_total: [AtomicU64; 5],
+    active_connections: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests that parsed successfully.
+    pub fn requests_total(&self) -> u64 {
+        self.requests_total.load(Ordering::Relaxed)
+    }
+
+    /// Responses sent with a status in the given class, e.g. `4` for `4xx`.
+    pub fn responses_total(&self, class: u16) -> u64 {
+        class
+            .checked_sub(1)
+            .and_then(|index| self.responses_total.get(usize::from(index)))
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_request(&self) {
+        self.requests_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_response(&self, status: StatusCode) {
+        let index = (status.as_u16() / 100).checked_sub(1);
+        if let Some(count) = index.and_then(|index| self.responses_total.get(usize::from(index))) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a connection as active until the returned guard is dropped.
+    pub(crate) fn connection(&self) -> ActiveConnection<'_> {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self)
+    }
+
+    /// Renders every counter in the Prometheus text exposition format,
+    /// followed by `queue`, the pool's view of its job queue.
+    pub fn render(&self, queue: &QueueStats) -> String {
+        let mut out = String::new();
+        metric(
+            &mut out,
+            "http_requests_total",
+            "counter",
+            "Requests received.",
+        );
+        let _ = writeln!(out, "http_requests_total {}", self.requests_total());
+
+        metric(
+            &mut out,
+            "http_responses_total",
+            "counter",
+            "Responses sent, by status class.",
+        );
+        for (class, count) in STATUS_CLASSES.iter().zip(&self.responses_total) {
+            let _ = writeln!(
+                out,
+                "http_responses_total{{class=\"{class}\"}} {}",
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        metric(
+            &mut out,
+            "http_active_connections",
+            "gauge",
+            "Connections currently being served.",
+        );
+        let _ = writeln!(out, "http_active_connections {}", self.active_connections());
+
+        metric(
+            &mut out,
+            "thread_pool_queued_jobs",
+            "gauge",
+            "Accepted connections waiting for a worker.",
+        );
+        let _ = writeln!(out, "thread_pool_queued_jobs {}", queue.queued);
+
+        metric(
+            &mut out,
+            "thread_pool_queue_wait_seconds",
+            "summary",
+            "Time accepted connections waited for a worker.",
+        );
+        let _ = writeln!(
+            out,
+            "thread_pool_queue_wait_seconds_sum {}",
+            queue.total_wait.as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "thread_pool_queue_wait_seconds_count {}",
+            queue.dequeued
+        );
+        metric(
+            &mut out,
+            "thread_pool_queue_wait_seconds_max",
+            "gauge",
+            "Longest time a connection waited for a worker.",
+        );
+        let _ = writeln!(
+            out,
+            "thread_pool_queue_wait_seconds_max {}",
+            queue.max_wait.as_secs_f64()
+        );
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+pub(crate) struct ActiveConnection<'a>(&'a Metrics);
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn renders_the_pool_queue() {
+        let metrics = Metrics::new();
+        metrics.record_request();
+        metrics.record_response(StatusCode::NOT_FOUND);
+        let out = metrics.render(&QueueStats {
+            queued: 3,
+            dequeued: 4,
+            total_wait: Duration::from_millis(500),
+            max_wait: Duration::from_millis(250),
+        });
+        assert!(out.contains("\nhttp_requests_total 1\n"), "{out}");
+        assert!(
+            out.contains("http_responses_total{class=\"4xx\"} 1\n"),
+            "{out}"
+        );
+        assert!(out.contains("\nthread_pool_queued_jobs 3\n"), "{out}");
+        assert!(
+            out.contains("\nthread_pool_queue_wait_seconds_count 4\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("\nthread_pool_queue_wait_seconds_max 0.25\n"),
+            "{out}"
+        );
+    }
+}
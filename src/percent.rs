@@ -0,0 +1,119 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// A `%` at this byte offset isn't followed by two hex digits.
+    InvalidEscape(usize),
+    /// The decoded bytes aren't valid UTF-8.
+    InvalidUtf8,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidEscape(offset) => {
+                write!(f, "invalid percent escape at offset {offset}")
+            }
+            DecodeError::InvalidUtf8 => write!(f, "percent-decoded bytes are not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes `%XX` escapes into bytes and validates the result as UTF-8.
+pub fn percent_decode(input: &str) -> Result<String, DecodeError> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let escaped = match bytes.get(i + 1..i + 3) {
+                Some([hi, lo]) => hex_value(*hi).zip(hex_value(*lo)),
+                _ => None,
+            };
+            let (hi, lo) = escaped.ok_or(DecodeError::InvalidEscape(i))?;
+            decoded.push(hi << 4 | lo);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| DecodeError::InvalidUtf8)
+}
+
+/// Like `percent_decode`, but keeps malformed escapes as they are and
+/// replaces invalid UTF-8, for comparing paths that may not decode.
+pub(crate) fn percent_decode_lossy(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i..i + 3) {
+            Some([b'%', hi, lo]) => hex_value(*hi).zip(hex_value(*lo)),
+            _ => None,
+        };
+        match escaped {
+            Some((hi, lo)) => {
+                decoded.push(hi << 4 | lo);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Escapes every byte outside the URI unreserved set (`A-Z a-z 0-9 - . _ ~`),
+/// so the result is safe to use as a single path segment.
+pub fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(percent_decode("my%20file.html").unwrap(), "my file.html");
+        assert_eq!(percent_decode("a%2Fb").unwrap(), "a/b");
+        assert_eq!(percent_decode("caf%C3%A9").unwrap(), "café");
+        assert_eq!(percent_decode("plain").unwrap(), "plain");
+    }
+
+    #[test]
+    fn rejects_malformed_escapes() {
+        assert_eq!(percent_decode("%ZZ"), Err(DecodeError::InvalidEscape(0)));
+        assert_eq!(percent_decode("a%G1"), Err(DecodeError::InvalidEscape(1)));
+        assert_eq!(percent_decode("100%"), Err(DecodeError::InvalidEscape(3)));
+        assert_eq!(percent_decode("%C3"), Err(DecodeError::InvalidUtf8));
+    }
+
+    #[test]
+    fn lossy_decoding_keeps_what_it_cannot_decode() {
+        assert_eq!(percent_decode_lossy("%61dmin%ZZ"), "admin%ZZ");
+        assert_eq!(percent_decode_lossy("%FF"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn encodes_everything_but_unreserved_bytes() {
+        assert_eq!(percent_encode("a b/c~d.é"), "a%20b%2Fc~d.%C3%A9");
+    }
+}
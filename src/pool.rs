@@ -0,0 +1,803 @@
+use std::{
+    any::Any,
+    fmt,
+    marker::Send,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::log;
+
+struct Worker {
+    id: u32,
+    /// Jobs this worker has run, readable from outside its thread.
+    processed: Arc<AtomicU64>,
+    thread: Option<JoinHandle<()>>,
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+enum Message {
+    /// A job and when it was submitted.
+    Run(Job, Instant),
+    /// Wakes an idle worker so it notices a pending retirement from `resize`.
+    Wake,
+}
+
+/// State shared between the pool handle and its workers.
+struct Shared {
+    receiver: Mutex<mpsc::Receiver<Message>>,
+    /// Jobs submitted but not yet finished, including the ones still queued.
+    pending: Mutex<usize>,
+    idle: Condvar,
+    /// Set when a shutdown timed out: workers drop queued jobs instead of running them.
+    discard: AtomicBool,
+    retirements: Mutex<Retirements>,
+    /// Jobs submitted and not yet picked up by a worker.
+    queued: AtomicUsize,
+    waits: WaitStats,
+}
+
+/// Workers on their way out after a shrinking `resize`.
+#[derive(Default)]
+struct Retirements {
+    /// Workers still to exit; the first ones between jobs claim the slots.
+    pending: usize,
+    /// Ids of workers that claimed a slot and haven't been joined yet.
+    retired: Vec<u32>,
+}
+
+/// Running totals of how long jobs sat in the queue, safe to update from any
+/// thread.
+#[derive(Debug, Default)]
+struct WaitStats {
+    count: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+impl WaitStats {
+    fn record(&self, wait: Duration) {
+        let micros = u64::try_from(wait.as_micros()).unwrap_or(u64::MAX);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Jobs recorded, their total wait and the longest single wait.
+    fn snapshot(&self) -> (u64, Duration, Duration) {
+        (
+            self.count.load(Ordering::Relaxed),
+            Duration::from_micros(self.total_micros.load(Ordering::Relaxed)),
+            Duration::from_micros(self.max_micros.load(Ordering::Relaxed)),
+        )
+    }
+}
+
+/// How busy the job queue is, from `ThreadPool::queue_stats`. Waits are
+/// measured from submission until a worker picks the job up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Jobs waiting for a worker right now.
+    pub queued: usize,
+    /// Jobs picked up by a worker so far.
+    pub dequeued: u64,
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+}
+
+/// Reads a pool's `QueueStats` without holding on to the pool itself, so
+/// they can be reported from another thread.
+#[derive(Clone)]
+pub(crate) struct QueueMonitor(Arc<Shared>);
+
+impl QueueMonitor {
+    pub(crate) fn stats(&self) -> QueueStats {
+        let (dequeued, total_wait, max_wait) = self.0.waits.snapshot();
+        QueueStats {
+            queued: self.0.queued.load(Ordering::SeqCst),
+            dequeued,
+            total_wait,
+            max_wait,
+        }
+    }
+}
+
+/// Locks `mutex` even if a thread panicked while holding it. The pool's
+/// locks are only held for a moment and never while running a job, so the
+/// data behind a poisoned one is still consistent, and one panic shouldn't
+/// take down every worker that locks it afterwards.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Shared {
+    /// Claims a pending retirement for worker `id`, if there is one.
+    fn take_retirement(&self, id: u32) -> bool {
+        let mut retirements = lock(&self.retirements);
+        if retirements.pending == 0 {
+            return false;
+        }
+        retirements.pending -= 1;
+        retirements.retired.push(id);
+        true
+    }
+
+    fn finish_job(&self) {
+        let mut pending = lock(&self.pending);
+        *pending -= 1;
+        if *pending == 0 {
+            self.idle.notify_all();
+        }
+    }
+}
+
+impl Worker {
+    fn new(id: u32, shared: Arc<Shared>) -> Worker {
+        let processed = Arc::new(AtomicU64::new(0));
+        let counter = processed.clone();
+        // Named so the thread shows up as `worker-N` in debuggers and panic messages.
+        let thread = thread::Builder::new()
+            .name(format!("worker-{id}"))
+            .spawn(move || work(id, &shared, &counter))
+            .expect("failed to spawn worker thread");
+        Worker {
+            id,
+            processed,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// The worker loop: runs jobs until the queue disconnects or the worker is retired.
+fn work(id: u32, shared: &Shared, processed: &AtomicU64) {
+    loop {
+        if shared.take_retirement(id) {
+            log::debug!("Worker {id} retired, exiting...");
+            break;
+        }
+        let message = lock(&shared.receiver).recv();
+        match message {
+            Ok(Message::Wake) => {}
+            Ok(Message::Run(f, submitted)) => {
+                shared.queued.fetch_sub(1, Ordering::SeqCst);
+                shared.waits.record(submitted.elapsed());
+                if !shared.discard.load(Ordering::SeqCst) {
+                    log::debug!("Got a new task worker_id:{id}");
+                    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                        log::error!("Job panicked on worker {id}: {}", panic_message(&*payload));
+                    }
+                    processed.fetch_add(1, Ordering::Relaxed);
+                }
+                shared.finish_job();
+            }
+            Err(_) => {
+                log::debug!("Worker {id} disconnected, exiting...");
+                break;
+            }
+        }
+    }
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "non-string panic payload"
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PoolError {
+    ZeroSize,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::ZeroSize => write!(f, "thread pool size must be greater than zero"),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExecuteError {
+    PoolShutDown,
+    /// Returned by `try_execute` when a bounded queue has no free slot.
+    QueueFull,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::PoolShutDown => write!(f, "thread pool is shut down"),
+            ExecuteError::QueueFull => write!(f, "thread pool queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for ExecuteError {}
+
+/// Returned by `ThreadPool::join` when worker threads panicked outside of a
+/// job, which catches its own panics.
+#[derive(Debug, PartialEq, Eq)]
+pub struct JoinError {
+    /// The id of each worker that panicked, with its panic message.
+    pub panicked: Vec<(u32, String)>,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} worker thread(s) panicked", self.panicked.len())
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+enum JobSender {
+    Unbounded(mpsc::Sender<Message>),
+    Bounded(mpsc::SyncSender<Message>),
+}
+
+impl JobSender {
+    fn send(&self, message: Message) -> Result<(), ExecuteError> {
+        match self {
+            JobSender::Unbounded(tx) => tx.send(message).map_err(|_| ExecuteError::PoolShutDown),
+            JobSender::Bounded(tx) => tx.send(message).map_err(|_| ExecuteError::PoolShutDown),
+        }
+    }
+
+    fn try_send(&self, message: Message) -> Result<(), ExecuteError> {
+        match self {
+            JobSender::Unbounded(tx) => tx.send(message).map_err(|_| ExecuteError::PoolShutDown),
+            JobSender::Bounded(tx) => tx.try_send(message).map_err(|err| match err {
+                mpsc::TrySendError::Full(_) => ExecuteError::QueueFull,
+                mpsc::TrySendError::Disconnected(_) => ExecuteError::PoolShutDown,
+            }),
+        }
+    }
+}
+
+pub struct ThreadPool {
+    tx: Option<JobSender>,
+    workers: Vec<Worker>,
+    shared: Arc<Shared>,
+    next_id: u32,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> Result<Self, PoolError> {
+        Self::with_queue_capacity(size, None)
+    }
+
+    /// Creates a pool whose job queue holds at most `queue_capacity` jobs.
+    ///
+    /// With a capacity `execute` blocks while the queue is full and
+    /// `try_execute` fails with `ExecuteError::QueueFull`; `None` keeps the
+    /// queue unbounded.
+    pub fn with_queue_capacity(
+        size: usize,
+        queue_capacity: Option<usize>,
+    ) -> Result<Self, PoolError> {
+        if size == 0 {
+            return Err(PoolError::ZeroSize);
+        }
+        let (tx, rx) = match queue_capacity {
+            Some(capacity) => {
+                let (tx, rx) = mpsc::sync_channel(capacity);
+                (JobSender::Bounded(tx), rx)
+            }
+            None => {
+                let (tx, rx) = mpsc::channel();
+                (JobSender::Unbounded(tx), rx)
+            }
+        };
+        let shared = Arc::new(Shared {
+            receiver: Mutex::new(rx),
+            pending: Mutex::new(0),
+            idle: Condvar::new(),
+            discard: AtomicBool::new(false),
+            retirements: Mutex::new(Retirements::default()),
+            queued: AtomicUsize::new(0),
+            waits: WaitStats::default(),
+        });
+        let mut pool = ThreadPool {
+            tx: Some(tx),
+            workers: Vec::with_capacity(size),
+            shared,
+            next_id: 1,
+        };
+        pool.spawn_workers(size);
+        Ok(pool)
+    }
+
+    fn spawn_workers(&mut self, count: usize) {
+        for _ in 0..count {
+            let id = self.next_id;
+            self.next_id += 1;
+            self.workers.push(Worker::new(id, self.shared.clone()));
+        }
+    }
+
+    /// Workers that take jobs, not counting ones retiring after a `resize`.
+    pub fn size(&self) -> usize {
+        let retirements = lock(&self.shared.retirements);
+        self.workers.len() - retirements.pending - retirements.retired.len()
+    }
+
+    /// Grows or shrinks the pool to `new_size` workers.
+    ///
+    /// Growing spawns new workers right away. Shrinking asks the excess
+    /// workers to exit once they finish their current job, without waiting
+    /// for them: a job can run for as long as it likes, as a kept-alive
+    /// connection does. Queued jobs are left for the remaining workers.
+    pub fn resize(&mut self, new_size: usize) -> Result<(), PoolError> {
+        if new_size == 0 {
+            return Err(PoolError::ZeroSize);
+        }
+        self.reap_retired();
+        let current = self.size();
+        if new_size >= current {
+            // Workers that haven't started retiring yet can simply stay.
+            let kept = {
+                let mut retirements = lock(&self.shared.retirements);
+                let kept = retirements.pending.min(new_size - current);
+                retirements.pending -= kept;
+                kept
+            };
+            self.spawn_workers(new_size - current - kept);
+            return Ok(());
+        }
+
+        let excess = current - new_size;
+        lock(&self.shared.retirements).pending += excess;
+        if let Some(tx) = &self.tx {
+            // Idle workers are blocked on the queue and need a message to
+            // notice. A full queue means none are idle, so a failed send is
+            // harmless: busy workers check for retirement between jobs.
+            for _ in 0..excess {
+                let _ = tx.try_send(Message::Wake);
+            }
+        }
+        Ok(())
+    }
+
+    /// Joins the workers that have retired so far, which have exited or
+    /// are about to.
+    fn reap_retired(&mut self) {
+        let retired = std::mem::take(&mut lock(&self.shared.retirements).retired);
+        for worker in &mut self.workers {
+            if retired.contains(&worker.id) {
+                if let Some(thread) = worker.thread.take() {
+                    let _ = thread.join();
+                }
+            }
+        }
+        self.workers.retain(|worker| !retired.contains(&worker.id));
+    }
+
+    pub fn execute<F>(&self, job: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit(Box::new(job), JobSender::send)
+    }
+
+    /// Like `execute`, but fails instead of blocking when the queue is full.
+    pub fn try_execute<F>(&self, job: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit(Box::new(job), JobSender::try_send)
+    }
+
+    fn submit(
+        &self,
+        job: Job,
+        send: fn(&JobSender, Message) -> Result<(), ExecuteError>,
+    ) -> Result<(), ExecuteError> {
+        let tx = self.tx.as_ref().ok_or(ExecuteError::PoolShutDown)?;
+        *lock(&self.shared.pending) += 1;
+        self.shared.queued.fetch_add(1, Ordering::SeqCst);
+        send(tx, Message::Run(job, Instant::now())).inspect_err(|_| {
+            self.shared.queued.fetch_sub(1, Ordering::SeqCst);
+            self.shared.finish_job();
+        })
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue_monitor().stats()
+    }
+
+    pub(crate) fn queue_monitor(&self) -> QueueMonitor {
+        QueueMonitor(self.shared.clone())
+    }
+
+    /// Returns `(worker id, jobs processed)` for every worker, in id order,
+    /// including ones still retiring after a `resize`. Jobs that panicked
+    /// count as processed.
+    pub fn worker_stats(&self) -> Vec<(u32, u64)> {
+        self.workers
+            .iter()
+            .map(|worker| (worker.id, worker.processed.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Stops accepting jobs, lets the workers finish every queued job and
+    /// waits for their threads to exit.
+    ///
+    /// Calling it again, or dropping the pool afterwards, does nothing more.
+    pub fn join(&mut self) -> Result<(), JoinError> {
+        drop(self.tx.take());
+
+        let mut panicked = Vec::new();
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                if let Err(payload) = thread.join() {
+                    panicked.push((worker.id, panic_message(&*payload).to_string()));
+                }
+            }
+        }
+        if panicked.is_empty() {
+            Ok(())
+        } else {
+            Err(JoinError { panicked })
+        }
+    }
+
+    /// Stops accepting jobs and waits up to `timeout` for the queued and
+    /// running ones to finish.
+    ///
+    /// Returns the number of jobs that were still pending when the timeout
+    /// elapsed. Those jobs are abandoned: queued ones are dropped without
+    /// running, and workers stuck in a job are detached instead of joined.
+    pub fn shutdown(mut self, timeout: Duration) -> usize {
+        drop(self.tx.take());
+
+        let pending = {
+            let pending = lock(&self.shared.pending);
+            let (pending, _) = self
+                .shared
+                .idle
+                .wait_timeout_while(pending, timeout, |pending| *pending > 0)
+                .unwrap_or_else(PoisonError::into_inner);
+            *pending
+        };
+
+        if pending > 0 {
+            self.shared.discard.store(true, Ordering::SeqCst);
+            for worker in &mut self.workers {
+                worker.thread.take();
+            }
+        }
+        pending
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Joins the workers like `join`. A worker that panicked is logged
+    /// rather than re-panicked, which could abort the process if the pool
+    /// is being dropped during unwinding.
+    fn drop(&mut self) {
+        if let Err(err) = self.join() {
+            for (id, message) in &err.panicked {
+                log::error!("Worker {id} panicked: {message}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_size_is_an_error() {
+        assert!(matches!(ThreadPool::new(0), Err(PoolError::ZeroSize)));
+        let pool = ThreadPool::new(4).unwrap();
+        assert_eq!(pool.size(), 4);
+    }
+
+    #[test]
+    fn execute_fails_once_the_pool_is_shut_down() {
+        let mut pool = ThreadPool::new(2).unwrap();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::scope(|scope| {
+            for n in 0..4 {
+                let (pool, done_tx) = (&pool, done_tx.clone());
+                scope.spawn(move || pool.execute(move || done_tx.send(n).unwrap()).unwrap());
+            }
+        });
+        drop(done_tx);
+        pool.join().unwrap();
+        let mut done: Vec<i32> = done_rx.iter().collect();
+        done.sort();
+        assert_eq!(done, [0, 1, 2, 3]);
+
+        assert_eq!(pool.execute(|| {}), Err(ExecuteError::PoolShutDown));
+    }
+
+    #[test]
+    fn worker_stats_add_up_to_the_jobs_run() {
+        let mut pool = ThreadPool::new(4).unwrap();
+        for _ in 0..100 {
+            pool.execute(|| {}).unwrap();
+        }
+        pool.join().unwrap();
+
+        let stats = pool.worker_stats();
+        let ids: Vec<u32> = stats.iter().map(|&(id, _)| id).collect();
+        assert_eq!(ids, [1, 2, 3, 4]);
+        assert_eq!(stats.iter().map(|&(_, count)| count).sum::<u64>(), 100);
+    }
+
+    #[test]
+    fn worker_threads_are_named() {
+        let mut pool = ThreadPool::new(3).unwrap();
+        let (name_tx, name_rx) = mpsc::channel();
+        for _ in 0..6 {
+            let name_tx = name_tx.clone();
+            pool.execute(move || {
+                let name = thread::current().name().map(str::to_string);
+                name_tx.send(name).unwrap();
+            })
+            .unwrap();
+        }
+        drop(name_tx);
+        pool.join().unwrap();
+        for name in name_rx {
+            let name = name.expect("worker thread has no name");
+            assert!(
+                matches!(name.as_str(), "worker-1" | "worker-2" | "worker-3"),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn worker_survives_a_panicking_job() {
+        let mut pool = ThreadPool::new(1).unwrap();
+        let flag = Arc::new(AtomicBool::new(false));
+        pool.execute(|| panic!("job failed")).unwrap();
+        let set = flag.clone();
+        pool.execute(move || set.store(true, Ordering::SeqCst))
+            .unwrap();
+        pool.join().unwrap();
+        assert!(flag.load(Ordering::SeqCst));
+        assert_eq!(pool.worker_stats(), [(1, 2)]);
+    }
+
+    #[test]
+    fn try_execute_fails_when_the_queue_is_full() {
+        let pool = ThreadPool::with_queue_capacity(1, Some(1)).unwrap();
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        })
+        .unwrap();
+        started_rx.recv().unwrap();
+
+        pool.try_execute(|| {}).unwrap();
+        assert_eq!(pool.try_execute(|| {}), Err(ExecuteError::QueueFull));
+        drop(release_tx);
+    }
+
+    #[test]
+    fn shutdown_reports_the_jobs_it_gave_up_on() {
+        let pool = ThreadPool::new(1).unwrap();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let ran = ran.clone();
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+        pool.execute(move || {
+            let _ = release_rx.recv();
+        })
+        .unwrap();
+        for _ in 0..2 {
+            let ran = ran.clone();
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+
+        assert_eq!(pool.shutdown(Duration::from_millis(200)), 3);
+        drop(release_tx);
+        assert_eq!(ran.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn shutdown_returns_zero_once_everything_finished() {
+        let pool = ThreadPool::new(2).unwrap();
+        for _ in 0..4 {
+            pool.execute(|| thread::sleep(Duration::from_millis(10)))
+                .unwrap();
+        }
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), 0);
+    }
+
+    /// Runs `count` jobs and returns how many of them ran.
+    fn run_jobs(pool: &ThreadPool, count: usize) -> usize {
+        let (done_tx, done_rx) = mpsc::channel();
+        for _ in 0..count {
+            let done_tx = done_tx.clone();
+            pool.execute(move || done_tx.send(()).unwrap()).unwrap();
+        }
+        drop(done_tx);
+        done_rx.iter().take(count).count()
+    }
+
+    #[test]
+    fn resize_grows_and_shrinks_the_pool() {
+        let mut pool = ThreadPool::new(2).unwrap();
+        pool.resize(5).unwrap();
+        assert_eq!(pool.size(), 5);
+        assert_eq!(pool.worker_stats().len(), 5);
+        assert_eq!(run_jobs(&pool, 20), 20);
+
+        pool.resize(2).unwrap();
+        assert_eq!(pool.size(), 2);
+        assert_eq!(run_jobs(&pool, 20), 20);
+
+        pool.resize(3).unwrap();
+        assert_eq!(pool.size(), 3);
+        assert_eq!(run_jobs(&pool, 20), 20);
+        assert_eq!(pool.resize(0), Err(PoolError::ZeroSize));
+        pool.join().unwrap();
+    }
+
+    #[test]
+    fn shrinking_does_not_wait_for_busy_workers() {
+        let mut pool = ThreadPool::new(2).unwrap();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        for _ in 0..2 {
+            let release_rx = release_rx.clone();
+            pool.execute(move || {
+                let _ = release_rx.lock().unwrap().recv();
+            })
+            .unwrap();
+        }
+
+        pool.resize(1).unwrap();
+        assert_eq!(pool.size(), 1);
+
+        drop(release_tx);
+        pool.join().unwrap();
+    }
+
+    #[test]
+    fn queue_stats_record_how_long_jobs_waited() {
+        let mut pool = ThreadPool::new(1).unwrap();
+        pool.execute(|| thread::sleep(Duration::from_millis(50)))
+            .unwrap();
+        pool.execute(|| {}).unwrap();
+        pool.join().unwrap();
+
+        let stats = pool.queue_stats();
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.dequeued, 2);
+        assert!(stats.max_wait >= Duration::from_millis(40), "{stats:?}");
+        assert!(stats.total_wait >= stats.max_wait);
+    }
+
+    /// Panics on another thread while holding `mutex`.
+    fn poison<T: Send>(mutex: &Mutex<T>) {
+        thread::scope(|scope| {
+            let result = scope
+                .spawn(|| {
+                    let _guard = mutex.lock();
+                    panic!("poisoning the lock");
+                })
+                .join();
+            assert!(result.is_err());
+        });
+        assert!(mutex.is_poisoned());
+    }
+
+    #[test]
+    fn workers_keep_going_after_a_lock_is_poisoned() {
+        let mut pool = ThreadPool::new(3).unwrap();
+        // Idle workers hold the receiver's lock while they wait for a job,
+        // so park every worker in one before poisoning it.
+        let entered = Arc::new(std::sync::Barrier::new(4));
+        let release = Arc::new(std::sync::Barrier::new(4));
+        for _ in 0..3 {
+            let (entered, release) = (entered.clone(), release.clone());
+            pool.execute(move || {
+                entered.wait();
+                release.wait();
+            })
+            .unwrap();
+        }
+        entered.wait();
+        poison(&pool.shared.receiver);
+        poison(&pool.shared.pending);
+        poison(&pool.shared.retirements);
+        release.wait();
+
+        assert_eq!(run_jobs(&pool, 20), 20);
+        pool.resize(1).unwrap();
+        assert_eq!(run_jobs(&pool, 5), 5);
+        assert_eq!(
+            pool.worker_stats()
+                .iter()
+                .map(|(_, jobs)| jobs)
+                .sum::<u64>(),
+            28
+        );
+        pool.join().unwrap();
+    }
+
+    #[test]
+    fn join_finishes_the_queued_jobs_and_can_be_repeated() {
+        let mut pool = ThreadPool::new(2).unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..8 {
+            let count = count.clone();
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(5));
+                count.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+        assert_eq!(pool.join(), Ok(()));
+        assert_eq!(count.load(Ordering::SeqCst), 8);
+        assert_eq!(pool.join(), Ok(()));
+    }
+
+    /// A panic payload that panics again when dropped, which the worker
+    /// does after catching the job's panic, so it kills the worker thread
+    /// itself.
+    struct PanicsOnDrop;
+
+    impl Drop for PanicsOnDrop {
+        fn drop(&mut self) {
+            panic!("worker died");
+        }
+    }
+
+    fn kill_worker(pool: &ThreadPool) {
+        pool.execute(|| panic::panic_any(PanicsOnDrop)).unwrap();
+    }
+
+    #[test]
+    fn join_reports_workers_that_panicked() {
+        let mut pool = ThreadPool::new(1).unwrap();
+        kill_worker(&pool);
+        assert_eq!(
+            pool.join(),
+            Err(JoinError {
+                panicked: vec![(1, "worker died".to_string())]
+            })
+        );
+        // Already joined, so dropping has nothing left to report.
+        drop(pool);
+    }
+
+    #[test]
+    fn dropping_a_pool_with_a_dead_worker_does_not_panic() {
+        let pool = ThreadPool::new(2).unwrap();
+        kill_worker(&pool);
+        let (done_tx, done_rx) = mpsc::channel();
+        pool.execute(move || done_tx.send(()).unwrap()).unwrap();
+        done_rx.recv().unwrap();
+        drop(pool);
+    }
+}
@@ -0,0 +1,368 @@
+//! Forwarding selected path prefixes to an upstream HTTP server.
+
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use crate::{
+    files::path_under_prefix,
+    log,
+    request::{self, Method, ParseError, Request},
+    response::Response,
+    status::StatusCode,
+};
+
+/// Upper bound on the wait for each read from or write to the upstream.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_LINE_BYTES: usize = 8 * 1024;
+
+/// Replays requests under any of `path_prefixes` to `upstream_addr` and
+/// relays the answer.
+#[derive(Debug, Clone)]
+pub struct ProxyHandler {
+    /// `host:port` of the upstream server.
+    pub upstream_addr: String,
+    /// Matched like `BasicAuth::path_prefixes`. The prefix is kept in the
+    /// forwarded path.
+    pub path_prefixes: Vec<String>,
+}
+
+impl ProxyHandler {
+    /// Whether `path` falls under one of the proxied prefixes.
+    pub fn proxies(&self, path: &str) -> bool {
+        self.path_prefixes
+            .iter()
+            .any(|prefix| path_under_prefix(path, prefix))
+    }
+
+    /// Forwards `request` upstream, answering `502 Bad Gateway` when the
+    /// upstream can't be reached or sends garbage, and `504 Gateway Timeout`
+    /// when it stops responding.
+    ///
+    /// The upstream's body is streamed to the client as it arrives rather
+    /// than read up front, so its size doesn't matter; once the response
+    /// has started, an upstream failure can only cut it short.
+    pub fn handle(&self, request: &Request) -> Response {
+        match self.forward(request) {
+            Ok(response) => response,
+            Err(err) => {
+                log::warn!("Proxying to {} failed: {err}", self.upstream_addr);
+                let timed_out = matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                );
+                if timed_out {
+                    Response::error(StatusCode::GATEWAY_TIMEOUT)
+                } else {
+                    Response::error(StatusCode::BAD_GATEWAY)
+                }
+            }
+        }
+    }
+
+    /// Middleware for `Router::wrap` that hands proxied paths to `handle`
+    /// instead of the routes.
+    pub fn middleware(
+        self,
+    ) -> impl Fn(&Request, &dyn Fn(&Request) -> Response) -> Response + Send + Sync + 'static {
+        move |request, next| {
+            if self.proxies(&request.path) {
+                self.handle(request)
+            } else {
+                next(request)
+            }
+        }
+    }
+
+    fn forward(&self, request: &Request) -> io::Result<Response> {
+        let stream = TcpStream::connect(self.upstream_addr.as_str())?;
+        stream.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+        stream.set_write_timeout(Some(UPSTREAM_TIMEOUT))?;
+
+        let mut writer = BufWriter::new(&stream);
+        write!(
+            writer,
+            "{} {} HTTP/1.1\r\n",
+            request.method.as_str(),
+            request.target
+        )?;
+        let mut had_length = false;
+        for (name, value) in &request.headers {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                had_length = true;
+            } else if !is_hop_by_hop(name) {
+                write!(writer, "{name}: {value}\r\n")?;
+            }
+        }
+        if had_length || !request.body.is_empty() {
+            write!(writer, "Content-Length: {}\r\n", request.body.len())?;
+        }
+        // One request per upstream connection keeps the response framing simple.
+        writer.write_all(b"Connection: close\r\n\r\n")?;
+        writer.write_all(&request.body)?;
+        writer.flush()?;
+        drop(writer);
+
+        read_response(BufReader::new(stream), request.method == Method::Head)
+    }
+}
+
+/// Headers that describe a single connection and must not be forwarded.
+fn is_hop_by_hop(name: &str) -> bool {
+    [
+        "Connection",
+        "Keep-Alive",
+        "Proxy-Authenticate",
+        "Proxy-Authorization",
+        "TE",
+        "Trailer",
+        "Transfer-Encoding",
+        "Upgrade",
+    ]
+    .iter()
+    .any(|hop| hop.eq_ignore_ascii_case(name))
+}
+
+/// Reads the upstream's status line and headers, leaving the body in
+/// `reader` for the response to stream.
+fn read_response(mut reader: impl BufRead + Send + 'static, head: bool) -> io::Result<Response> {
+    let (status, headers) = loop {
+        let (status, headers) = read_head(&mut reader)?;
+        // Interim responses such as `100 Continue` are followed by the real one.
+        if !(100..200).contains(&status.as_u16()) {
+            break (status, headers);
+        }
+    };
+
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+    let chunked = header("Transfer-Encoding")
+        .is_some_and(|value| value.to_ascii_lowercase().contains("chunked"));
+    let length = match header("Content-Length") {
+        Some(length) if !chunked => Some(
+            length
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| invalid("bad Content-Length"))?,
+        ),
+        _ => None,
+    };
+    let code = status.as_u16();
+    let bodiless = head || code == 204 || code == 304;
+
+    let mut response = Response::new(status);
+    response.headers = headers
+        .into_iter()
+        .filter(|(name, _)| !is_hop_by_hop(name))
+        .filter(|(name, _)| !(chunked && name.eq_ignore_ascii_case("Content-Length")))
+        .collect();
+    Ok(if bodiless {
+        response
+    } else if chunked {
+        response.chunked(Dechunked {
+            reader,
+            remaining: 0,
+            done: false,
+        })
+    } else if let Some(length) = length {
+        response.stream(reader, length)
+    } else {
+        // Without either, the body runs until the upstream closes.
+        response.chunked(reader)
+    })
+}
+
+fn read_head(reader: &mut impl BufRead) -> io::Result<(StatusCode, Vec<(String, String)>)> {
+    let status_line = read_line(reader)?;
+    let status = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .filter(|code| (100..600).contains(code))
+        .ok_or_else(|| invalid("malformed status line"))?;
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    Ok((StatusCode::from_u16(status), headers))
+}
+
+/// A chunked upstream body, decoded as the client's response is written.
+struct Dechunked<R> {
+    reader: R,
+    /// Bytes left in the current chunk.
+    remaining: u64,
+    /// Set once the last chunk and the trailers have been read.
+    done: bool,
+}
+
+impl<R: BufRead> Read for Dechunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.remaining = request::read_chunk_size(&mut self.reader, MAX_LINE_BYTES)
+                .map_err(upstream_error)?;
+            if self.remaining == 0 {
+                request::skip_trailers(&mut self.reader, MAX_LINE_BYTES).map_err(upstream_error)?;
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let max = usize::try_from(self.remaining).map_or(buf.len(), |left| left.min(buf.len()));
+        let n = self.reader.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(upstream_error(ParseError::UnexpectedEof));
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            request::read_chunk_end(&mut self.reader, MAX_LINE_BYTES).map_err(upstream_error)?;
+        }
+        Ok(n)
+    }
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    request::read_line(reader, MAX_LINE_BYTES)
+        .map_err(upstream_error)?
+        .ok_or_else(|| upstream_error(ParseError::UnexpectedEof))
+}
+
+fn upstream_error(err: ParseError) -> io::Error {
+    match err {
+        ParseError::Io(err) => err,
+        ParseError::ConnectionClosed | ParseError::UnexpectedEof => io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "upstream closed the connection",
+        ),
+        ParseError::LineTooLong => invalid("line that is too long"),
+        ParseError::InvalidChunk(_) => invalid("malformed chunk"),
+        _ => invalid("line that is not UTF-8"),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("upstream sent a {message}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, net::TcpListener, thread};
+
+    use super::*;
+
+    /// Starts an upstream that answers a single request with `response`.
+    fn stub_upstream(response: &'static [u8]) -> ProxyHandler {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            while !read_line(&mut reader).unwrap().is_empty() {}
+            let _ = (&stream).write_all(response);
+        });
+        ProxyHandler {
+            upstream_addr,
+            path_prefixes: vec!["/api".to_string()],
+        }
+    }
+
+    fn get(path: &str) -> Request {
+        let raw = format!("GET {path} HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    /// Writes `response` as the server would and returns the body.
+    fn body_of(mut response: Response) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        response.write_to(&mut out)?;
+        let end = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        Ok(out[end..].to_vec())
+    }
+
+    #[test]
+    fn relays_the_upstream_body() {
+        let proxy = stub_upstream(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello",
+        );
+        let response = proxy.handle(&get("/api/greeting"));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.header_value("Content-Type"), Some("text/plain"));
+        assert!(response.is_streamed());
+        assert_eq!(body_of(response).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn decodes_a_chunked_upstream_body() {
+        let proxy = stub_upstream(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3\r\nhel\r\n2;ext=1\r\nlo\r\n0\r\nX-Trailer: 1\r\n\r\n",
+        );
+        let mut response = proxy.handle(&get("/api"));
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.ends_with("\r\n\r\n3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n"),
+            "{out}"
+        );
+    }
+
+    #[test]
+    fn huge_declared_length_is_not_allocated() {
+        let proxy = stub_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 100000000000\r\n\r\nshort");
+        let response = proxy.handle(&get("/api"));
+        assert_eq!(
+            response.header_value("Content-Length"),
+            Some("100000000000")
+        );
+        let err = body_of(response).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn huge_chunk_size_is_not_allocated() {
+        let proxy = stub_upstream(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffff\r\nabc",
+        );
+        let err = body_of(proxy.handle(&get("/api"))).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn malformed_chunk_size_cuts_the_body_short() {
+        let proxy = stub_upstream(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n");
+        let err = body_of(proxy.handle(&get("/api"))).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unreachable_upstream_is_a_bad_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let proxy = ProxyHandler {
+            upstream_addr,
+            path_prefixes: vec!["/api".to_string()],
+        };
+        assert_eq!(proxy.handle(&get("/api")).status, StatusCode::BAD_GATEWAY);
+    }
+}
@@ -0,0 +1,177 @@
+//! Per-client token-bucket rate limiting.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    net::IpAddr,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Clients tracked at once. Beyond this the one heard from least recently
+/// is forgotten, which at worst hands it a fresh bucket.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Each client may make `burst` requests at once, after which its budget
+/// refills at `requests_per_second`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Checks that the limit lets requests through at all: a rate that
+    /// isn't a positive number would never refill a bucket.
+    pub(crate) fn validate(&self) -> Result<(), InvalidRateLimit> {
+        if !(self.requests_per_second.is_finite() && self.requests_per_second > 0.0) {
+            return Err(InvalidRateLimit("requests_per_second must be positive"));
+        }
+        if self.burst == 0 {
+            return Err(InvalidRateLimit("burst must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct InvalidRateLimit(&'static str);
+
+impl fmt::Display for InvalidRateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid rate limit: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidRateLimit {}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_client: HashMap<IpAddr, Bucket>,
+    /// Every tracked client by when it was last seen, oldest first.
+    by_age: BTreeSet<(Instant, IpAddr)>,
+}
+
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// `limit` must have passed `RateLimit::validate`.
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Takes a token from `ip`'s bucket. When it's empty, returns how long
+    /// until the next token is available instead.
+    pub(crate) fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let burst = f64::from(self.limit.burst);
+        let mut buckets = self.lock();
+        let Buckets { by_client, by_age } = &mut *buckets;
+        if by_client.len() >= MAX_TRACKED_CLIENTS && !by_client.contains_key(&ip) {
+            if let Some((_, oldest)) = by_age.pop_first() {
+                by_client.remove(&oldest);
+            }
+        }
+
+        let bucket = by_client.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        by_age.remove(&(bucket.updated, ip));
+        by_age.insert((now, ip));
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let missing = 1.0 - bucket.tokens;
+        Err(Duration::from_secs_f64(
+            missing / self.limit.requests_per_second,
+        ))
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.limit.requests_per_second).min(f64::from(self.limit.burst))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Buckets> {
+        self.buckets.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    fn limiter(requests_per_second: f64, burst: u32) -> RateLimiter {
+        RateLimiter::new(RateLimit {
+            requests_per_second,
+            burst,
+        })
+    }
+
+    #[test]
+    fn requests_beyond_the_burst_are_limited() {
+        let limiter = limiter(1.0, 3);
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let results: Vec<_> = (0..5).map(|_| limiter.check(ip)).collect();
+        assert!(results[..3].iter().all(Result::is_ok));
+        let retry_after = results[3].unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+        assert!(results[4].is_err());
+        // Other clients have buckets of their own.
+        assert!(limiter.check(IpAddr::from([127, 0, 0, 2])).is_ok());
+    }
+
+    #[test]
+    fn rates_that_never_refill_are_invalid() {
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let limit = RateLimit {
+                requests_per_second: rate,
+                burst: 5,
+            };
+            assert!(limit.validate().is_err(), "{rate}");
+        }
+        let limit = RateLimit {
+            requests_per_second: 5.0,
+            burst: 0,
+        };
+        assert!(limit.validate().is_err());
+        let limit = RateLimit {
+            requests_per_second: 0.5,
+            burst: 1,
+        };
+        assert!(limit.validate().is_ok());
+    }
+
+    #[test]
+    fn tracked_clients_are_capped() {
+        let limiter = limiter(1.0, 1);
+        let first = IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0));
+        assert!(limiter.check(first).is_ok());
+        for i in 1..=MAX_TRACKED_CLIENTS as u16 {
+            let ip = IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 1, i));
+            let _ = limiter.check(ip);
+        }
+        let buckets = limiter.lock();
+        assert_eq!(buckets.by_client.len(), MAX_TRACKED_CLIENTS);
+        assert_eq!(buckets.by_age.len(), MAX_TRACKED_CLIENTS);
+        // The oldest client made room; its next request starts afresh.
+        assert!(!buckets.by_client.contains_key(&first));
+    }
+}
@@ -0,0 +1,757 @@
+use std::{
+    fmt,
+    io::{self, BufRead, Read},
+    net::{Ipv4Addr, SocketAddr},
+};
+
+use crate::percent::{percent_decode, DecodeError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+    Head,
+    Options,
+    Trace,
+    Other(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Head => "HEAD",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Other(method) => method,
+        }
+    }
+}
+
+impl From<&str> for Method {
+    fn from(method: &str) -> Self {
+        match method {
+            "GET" => Method::Get,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "HEAD" => Method::Head,
+            "OPTIONS" => Method::Options,
+            "TRACE" => Method::Trace,
+            other => Method::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Io(io::Error),
+    /// The client closed the connection before sending a request line.
+    ConnectionClosed,
+    /// The client closed the connection in the middle of the headers or body.
+    UnexpectedEof,
+    InvalidRequestLine(String),
+    InvalidHeader(String),
+    InvalidQuery(DecodeError),
+    /// The request line or a header line is longer than `ParseLimits::max_line_bytes`.
+    LineTooLong,
+    /// The header section is larger than `ParseLimits::max_header_bytes`.
+    HeadersTooLarge,
+    /// The request has more header lines than `ParseLimits::max_headers`.
+    TooManyHeaders,
+    /// The declared `Content-Length`, or the chunks received so far, exceed
+    /// `ParseLimits::max_body_bytes`.
+    BodyTooLarge,
+    /// A chunked body has a malformed chunk size line or chunk ending.
+    InvalidChunk(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(err) => write!(f, "io error: {err}"),
+            ParseError::ConnectionClosed => write!(f, "connection closed before request line"),
+            ParseError::UnexpectedEof => write!(f, "connection closed in the middle of a request"),
+            ParseError::InvalidRequestLine(line) => write!(f, "invalid request line: {line:?}"),
+            ParseError::InvalidHeader(line) => write!(f, "invalid header line: {line:?}"),
+            ParseError::InvalidQuery(err) => write!(f, "invalid query string: {err}"),
+            ParseError::LineTooLong => write!(f, "request line or header line too long"),
+            ParseError::HeadersTooLarge => write!(f, "header section too large"),
+            ParseError::TooManyHeaders => write!(f, "too many headers"),
+            ParseError::BodyTooLarge => write!(f, "request body too large"),
+            ParseError::InvalidChunk(line) => write!(f, "invalid chunk: {line:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Io(err) => Some(err),
+            ParseError::InvalidQuery(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> Self {
+        ParseError::Io(err)
+    }
+}
+
+/// Caps on how much of a request `Request::parse_with_limits` will buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    /// Longest request line or header line accepted, not counting the line ending.
+    pub max_line_bytes: usize,
+    /// Largest header section accepted, counting every header line and its line ending.
+    pub max_header_bytes: usize,
+    /// Most header lines accepted, so many tiny headers can't slip under
+    /// `max_header_bytes` and still cost a lot to process.
+    pub max_headers: usize,
+    /// Largest body accepted, whether sized by `Content-Length` or chunked.
+    pub max_body_bytes: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_line_bytes: 8 * 1024,
+            max_header_bytes: 16 * 1024,
+            max_headers: 100,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    /// The request target exactly as it appeared on the request line. `*`
+    /// for an `OPTIONS` request about the server as a whole.
+    pub target: String,
+    /// The request target up to the first `?`.
+    pub path: String,
+    /// Decoded query parameters in the order they appeared, repeated keys included.
+    pub query: Vec<(String, String)>,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    /// Exactly `Content-Length` bytes, the decoded chunks of a chunked
+    /// body, or empty when the request has neither.
+    pub body: Vec<u8>,
+    /// Decoded values captured by `{name}` segments of the matched route.
+    pub path_params: Vec<(String, String)>,
+    /// The connected client. Unspecified (`0.0.0.0:0`) when it has no
+    /// address, as on a Unix socket, or it couldn't be read. Parsing
+    /// leaves it unspecified for the server to fill in.
+    pub remote_addr: SocketAddr,
+}
+
+impl Request {
+    /// Reads the request line, the header section and, when `Content-Length`
+    /// or `Transfer-Encoding: chunked` is present, the body that follows.
+    pub fn parse(reader: &mut impl BufRead) -> Result<Request, ParseError> {
+        Self::parse_with_limits(reader, &ParseLimits::default())
+    }
+
+    /// Like `parse`, but fails as soon as the request outgrows `limits`
+    /// instead of buffering arbitrarily long lines.
+    pub fn parse_with_limits(
+        reader: &mut impl BufRead,
+        limits: &ParseLimits,
+    ) -> Result<Request, ParseError> {
+        let mut request = Self::parse_head_with_limits(reader, limits)?;
+        request.read_body(reader, limits)?;
+        Ok(request)
+    }
+
+    /// Reads the request line and the header section but not the body, so
+    /// the caller can respond to `Expect: 100-continue` before the client
+    /// sends it. `read_body` reads it afterwards.
+    pub fn parse_head_with_limits(
+        reader: &mut impl BufRead,
+        limits: &ParseLimits,
+    ) -> Result<Request, ParseError> {
+        let request_line = match read_raw_line(reader, limits.max_line_bytes)? {
+            Some(line) => String::from_utf8(line).map_err(|err| {
+                ParseError::InvalidRequestLine(String::from_utf8_lossy(err.as_bytes()).into_owned())
+            })?,
+            None => return Err(ParseError::ConnectionClosed),
+        };
+
+        let mut tokens = request_line.split_whitespace();
+        let (method, target, version) = match (tokens.next(), tokens.next(), tokens.next()) {
+            (Some(method), Some(target), Some(version))
+                if tokens.next().is_none() && version.starts_with("HTTP/") =>
+            {
+                (method, target, version)
+            }
+            _ => return Err(ParseError::InvalidRequestLine(request_line)),
+        };
+        // The asterisk form only means something to OPTIONS.
+        if target == "*" && method != "OPTIONS" {
+            return Err(ParseError::InvalidRequestLine(request_line));
+        }
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, parse_query(query).map_err(ParseError::InvalidQuery)?),
+            None => (target, Vec::new()),
+        };
+        let mut request = Request {
+            method: Method::from(method),
+            target: target.to_string(),
+            path: path.to_string(),
+            query,
+            version: version.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            path_params: Vec::new(),
+            remote_addr: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        };
+
+        let mut header_bytes = 0;
+        loop {
+            let line =
+                read_line(reader, limits.max_line_bytes)?.ok_or(ParseError::UnexpectedEof)?;
+            if line.is_empty() {
+                break;
+            }
+            // Obsolete line folding continues the previous header on a line
+            // starting with whitespace. RFC 9112 lets a server reject it.
+            if line.starts_with([' ', '\t']) {
+                return Err(ParseError::InvalidHeader(line));
+            }
+            header_bytes += line.len() + 2;
+            if header_bytes > limits.max_header_bytes {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            if request.headers.len() == limits.max_headers {
+                return Err(ParseError::TooManyHeaders);
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| ParseError::InvalidHeader(line.clone()))?;
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(ParseError::InvalidHeader(line));
+            }
+            request
+                .headers
+                .push((name.to_string(), value.trim().to_string()));
+        }
+        Ok(request)
+    }
+
+    /// Reads the body that follows the header section. A `Content-Length`
+    /// body fails before any of it is read when it's too large, a chunked
+    /// one as soon as the chunks add up to too much.
+    pub fn read_body(
+        &mut self,
+        reader: &mut impl BufRead,
+        limits: &ParseLimits,
+    ) -> Result<(), ParseError> {
+        if self.is_chunked()? {
+            self.body = read_chunked(reader, limits)?;
+        } else if let Some(len) = self.content_length()? {
+            if len > limits.max_body_bytes as u64 {
+                return Err(ParseError::BodyTooLarge);
+            }
+            reader.take(len).read_to_end(&mut self.body)?;
+            if self.body.len() as u64 != len {
+                return Err(ParseError::UnexpectedEof);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the body is chunked. Any other transfer coding, a repeated
+    /// `Transfer-Encoding`, or one sent alongside `Content-Length`, any of
+    /// which could be used to smuggle a second request past a proxy, is
+    /// rejected.
+    pub(crate) fn is_chunked(&self) -> Result<bool, ParseError> {
+        let mut values = self.header_all("Transfer-Encoding");
+        let Some(value) = values.next() else {
+            return Ok(false);
+        };
+        if !value.eq_ignore_ascii_case("chunked")
+            || values.next().is_some()
+            || self.header("Content-Length").is_some()
+        {
+            return Err(ParseError::InvalidHeader(format!(
+                "Transfer-Encoding: {value}"
+            )));
+        }
+        Ok(true)
+    }
+
+    /// The body length from `Content-Length`. Repeated headers, or a
+    /// comma-separated list, are accepted only when every value is the
+    /// same, as RFC 9112 requires; a server and a proxy picking different
+    /// ones would disagree on where the next request starts.
+    pub(crate) fn content_length(&self) -> Result<Option<u64>, ParseError> {
+        let mut length = None;
+        for value in self
+            .header_all("Content-Length")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+        {
+            let invalid = || ParseError::InvalidHeader(format!("Content-Length: {value}"));
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            let value: u64 = value.parse().map_err(|_| invalid())?;
+            if length.is_some_and(|length| length != value) {
+                return Err(invalid());
+            }
+            length = Some(value);
+        }
+        Ok(length)
+    }
+
+    /// Returns the value of the first header named `name`, compared
+    /// ASCII-case-insensitively as HTTP requires. Repeated headers are kept
+    /// in `headers`; `header_all` returns them all.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns every value of the headers named `name`, in the order they
+    /// were sent, for headers such as `X-Forwarded-For` that may repeat.
+    pub fn header_all<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl DoubleEndedIterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the segment captured by `{name}` in the matched route's path.
+    pub fn path_param(&self, name: &str) -> Option<&str> {
+        self.path_params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the value of the first query parameter named `key`.
+    pub fn query_param(&self, key: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Splits a query string into decoded pairs, treating `+` as a space as
+/// HTML forms do.
+fn parse_query(query: &str) -> Result<Vec<(String, String)>, DecodeError> {
+    let decode = |component: &str| percent_decode(&component.replace('+', " "));
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((decode(key)?, decode(value)?))
+        })
+        .collect()
+}
+
+/// Decodes a chunked body, skipping chunk extensions and trailers.
+fn read_chunked(reader: &mut impl BufRead, limits: &ParseLimits) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    loop {
+        let size = read_chunk_size(reader, limits.max_line_bytes)?;
+        if size == 0 {
+            skip_trailers(reader, limits.max_line_bytes)?;
+            return Ok(body);
+        }
+        if body.len() as u64 + size > limits.max_body_bytes as u64 {
+            return Err(ParseError::BodyTooLarge);
+        }
+        let start = body.len();
+        reader.take(size).read_to_end(&mut body)?;
+        if (body.len() - start) as u64 != size {
+            return Err(ParseError::UnexpectedEof);
+        }
+        read_chunk_end(reader, limits.max_line_bytes)?;
+    }
+}
+
+/// Reads the line that starts a chunk and returns the chunk's size,
+/// ignoring any chunk extensions. `0` marks the last chunk.
+pub(crate) fn read_chunk_size(
+    reader: &mut impl BufRead,
+    max_line_bytes: usize,
+) -> Result<u64, ParseError> {
+    let line = read_raw_line(reader, max_line_bytes)?.ok_or(ParseError::UnexpectedEof)?;
+    let line = String::from_utf8_lossy(&line);
+    let size = line.split(';').next().unwrap_or_default().trim();
+    // `from_str_radix` alone would also take a leading `+`.
+    Some(size)
+        .filter(|size| !size.is_empty() && size.bytes().all(|b| b.is_ascii_hexdigit()))
+        .and_then(|size| u64::from_str_radix(size, 16).ok())
+        .ok_or_else(|| ParseError::InvalidChunk(line.into_owned()))
+}
+
+/// Reads the line ending that follows a chunk's data.
+pub(crate) fn read_chunk_end(
+    reader: &mut impl BufRead,
+    max_line_bytes: usize,
+) -> Result<(), ParseError> {
+    match read_raw_line(reader, max_line_bytes)? {
+        Some(end) if end.is_empty() => Ok(()),
+        Some(end) => Err(ParseError::InvalidChunk(
+            String::from_utf8_lossy(&end).into_owned(),
+        )),
+        None => Err(ParseError::UnexpectedEof),
+    }
+}
+
+/// Skips the trailer fields after the last chunk, up to the blank line
+/// that ends the body.
+pub(crate) fn skip_trailers(
+    reader: &mut impl BufRead,
+    max_line_bytes: usize,
+) -> Result<(), ParseError> {
+    while !read_raw_line(reader, max_line_bytes)?
+        .ok_or(ParseError::UnexpectedEof)?
+        .is_empty()
+    {}
+    Ok(())
+}
+
+/// Reads a single line without its trailing `\r\n`, returning `None` at EOF.
+///
+/// At most `max_len` bytes plus the line ending are read, so an endless line
+/// fails with `LineTooLong` rather than exhausting memory. A line that
+/// isn't UTF-8 fails with `InvalidHeader`.
+pub(crate) fn read_line(
+    reader: &mut impl BufRead,
+    max_len: usize,
+) -> Result<Option<String>, ParseError> {
+    match read_raw_line(reader, max_len)? {
+        Some(line) => String::from_utf8(line).map(Some).map_err(|err| {
+            ParseError::InvalidHeader(String::from_utf8_lossy(err.as_bytes()).into_owned())
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Like `read_line`, but leaves the bytes undecoded.
+fn read_raw_line(reader: &mut impl BufRead, max_len: usize) -> Result<Option<Vec<u8>>, ParseError> {
+    let mut line = Vec::new();
+    let limit = max_len as u64 + 2;
+    if reader.take(limit).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.ends_with(b"\n") {
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+    }
+    if line.len() > max_len {
+        return Err(ParseError::LineTooLong);
+    }
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &[u8]) -> Result<Request, ParseError> {
+        Request::parse(&mut &raw[..])
+    }
+
+    #[test]
+    fn parses_a_get_request_with_two_headers() {
+        let request =
+            parse(b"GET /index.html HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n").unwrap();
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.path, "/index.html");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(
+            request.headers,
+            [
+                ("Host".to_string(), "example.com".to_string()),
+                ("Accept".to_string(), "*/*".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn header_lookup_ignores_case() {
+        let request = parse(
+            b"POST /echo HTTP/1.1\r\ncontent-LENGTH: 2\r\nCONNECTION: close\r\n\
+              X-Mixed-Case: yes\r\n\r\nhi",
+        )
+        .unwrap();
+        for name in ["x-mixed-case", "X-MIXED-CASE", "X-Mixed-Case"] {
+            assert_eq!(request.header(name), Some("yes"), "{name}");
+        }
+        assert_eq!(request.header("Connection"), Some("close"));
+        assert_eq!(request.header("X-Missing"), None);
+        assert_eq!(request.content_length().unwrap(), Some(2));
+        assert_eq!(request.body, b"hi");
+    }
+
+    #[test]
+    fn unknown_methods_are_kept() {
+        let request = parse(b"PATCH / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.method, Method::Other("PATCH".to_string()));
+    }
+
+    #[test]
+    fn query_string_is_split_off_the_path() {
+        let request = parse(b"GET /x?a=1&b=two&a=3 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/x");
+        assert_eq!(
+            request.query,
+            [
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "two".to_string()),
+                ("a".to_string(), "3".to_string()),
+            ]
+        );
+        assert_eq!(request.query_param("a"), Some("1"));
+        assert_eq!(request.query_param("c"), None);
+    }
+
+    #[test]
+    fn query_values_are_percent_decoded() {
+        let request = parse(b"GET /search?q=caf%C3%A9&flag HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.query_param("q"), Some("café"));
+        assert_eq!(request.query_param("flag"), Some(""));
+        assert!(matches!(
+            parse(b"GET /search?q=%ZZ HTTP/1.1\r\n\r\n"),
+            Err(ParseError::InvalidQuery(_))
+        ));
+    }
+
+    #[test]
+    fn empty_request_line_is_rejected() {
+        assert!(matches!(
+            parse(b"\r\n\r\n"),
+            Err(ParseError::InvalidRequestLine(line)) if line.is_empty()
+        ));
+    }
+
+    #[test]
+    fn malformed_request_lines_are_rejected() {
+        for raw in [
+            &b"GET\r\n\r\n"[..],
+            b"GET /\r\n\r\n",
+            b"GET / HTTP/1.1 extra\r\n\r\n",
+            b"GET / FTP/1.0\r\n\r\n",
+        ] {
+            assert!(
+                matches!(parse(raw), Err(ParseError::InvalidRequestLine(_))),
+                "{:?}",
+                String::from_utf8_lossy(raw)
+            );
+        }
+    }
+
+    #[test]
+    fn asterisk_target_is_only_for_options() {
+        let request = parse(b"OPTIONS * HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.method, Method::Options);
+        assert_eq!(request.target, "*");
+        assert!(matches!(
+            parse(b"GET * HTTP/1.1\r\n\r\n"),
+            Err(ParseError::InvalidRequestLine(line)) if line == "GET * HTTP/1.1"
+        ));
+    }
+
+    #[test]
+    fn closed_connection_is_not_a_malformed_request() {
+        assert!(matches!(parse(b""), Err(ParseError::ConnectionClosed)));
+    }
+
+    #[test]
+    fn non_utf8_request_line_is_invalid() {
+        assert!(matches!(
+            parse(b"GET /\xff HTTP/1.1\r\n\r\n"),
+            Err(ParseError::InvalidRequestLine(_))
+        ));
+    }
+
+    #[test]
+    fn overlong_request_line_is_rejected_without_reading_it_all() {
+        // An endless request line with no newline in sight.
+        let mut endless = io::BufReader::new(io::repeat(b'a'));
+        assert!(matches!(
+            Request::parse(&mut endless),
+            Err(ParseError::LineTooLong)
+        ));
+    }
+
+    #[test]
+    fn header_section_is_capped() {
+        let limits = ParseLimits {
+            max_header_bytes: 64,
+            ..ParseLimits::default()
+        };
+        let header = format!("X-Filler: {}\r\n", "x".repeat(20));
+        let raw = format!("GET / HTTP/1.1\r\n{}\r\n", header.repeat(3));
+        assert!(matches!(
+            Request::parse_with_limits(&mut raw.as_bytes(), &limits),
+            Err(ParseError::HeadersTooLarge)
+        ));
+        let raw = format!("GET / HTTP/1.1\r\n{}\r\n", header.repeat(2));
+        assert!(Request::parse_with_limits(&mut raw.as_bytes(), &limits).is_ok());
+    }
+
+    #[test]
+    fn header_count_is_capped() {
+        let headers = |count: usize| {
+            let lines: String = (0..count).map(|i| format!("X-{i}: y\r\n")).collect();
+            format!("GET / HTTP/1.1\r\n{lines}\r\n")
+        };
+        assert!(matches!(
+            parse(headers(200).as_bytes()),
+            Err(ParseError::TooManyHeaders)
+        ));
+        assert_eq!(parse(headers(100).as_bytes()).unwrap().headers.len(), 100);
+    }
+
+    #[test]
+    fn body_is_read_up_to_content_length() {
+        let request =
+            parse(b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello, and more").unwrap();
+        assert_eq!(request.body, b"hello");
+
+        let request = parse(b"POST /echo HTTP/1.1\r\n\r\nnot a body").unwrap();
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn body_over_the_limit_is_rejected() {
+        let limits = ParseLimits {
+            max_body_bytes: 4,
+            ..ParseLimits::default()
+        };
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        assert!(matches!(
+            Request::parse_with_limits(&mut &raw[..], &limits),
+            Err(ParseError::BodyTooLarge)
+        ));
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel";
+        assert!(matches!(parse(raw), Err(ParseError::UnexpectedEof)));
+    }
+
+    #[test]
+    fn repeated_headers_are_all_kept_in_order() {
+        let raw = b"GET / HTTP/1.1\r\nX-Forwarded-For: 192.0.2.1\r\nHost: example.com\r\n\
+                    x-forwarded-for: 198.51.100.2, 203.0.113.3\r\n\r\n";
+        let request = parse(raw).unwrap();
+        assert_eq!(
+            request.header_all("X-Forwarded-For").collect::<Vec<_>>(),
+            ["192.0.2.1", "198.51.100.2, 203.0.113.3"]
+        );
+        assert_eq!(request.header("X-Forwarded-For"), Some("192.0.2.1"));
+        assert_eq!(request.header_all("Accept").count(), 0);
+    }
+
+    #[test]
+    fn folded_headers_are_rejected() {
+        for fold in [" ", "\t"] {
+            let raw = format!("GET / HTTP/1.1\r\nX-Long: first\r\n{fold}second\r\n\r\n");
+            assert!(
+                matches!(
+                    parse(raw.as_bytes()),
+                    Err(ParseError::InvalidHeader(line)) if line == format!("{fold}second")
+                ),
+                "{raw:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn chunked_body_is_assembled() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nX-Trailer: 1\r\n\r\n\
+                    GET /next HTTP/1.1\r\n\r\n";
+        let mut reader = &raw[..];
+        let request = Request::parse(&mut reader).unwrap();
+        assert_eq!(request.body, b"hello, world");
+        assert_eq!(reader, b"GET /next HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn malformed_chunks_are_rejected() {
+        for chunks in [
+            &b"zz\r\nhello\r\n0\r\n\r\n"[..],
+            b"+5\r\nhello\r\n0\r\n\r\n",
+            b"2\r\nhello\r\n0\r\n\r\n",
+        ] {
+            let mut raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            raw.extend_from_slice(chunks);
+            assert!(
+                matches!(parse(&raw), Err(ParseError::InvalidChunk(_))),
+                "{:?}",
+                String::from_utf8_lossy(chunks)
+            );
+        }
+    }
+
+    #[test]
+    fn chunked_body_over_the_limit_is_rejected() {
+        let limits = ParseLimits {
+            max_body_bytes: 8,
+            ..ParseLimits::default()
+        };
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n";
+        assert!(matches!(
+            Request::parse_with_limits(&mut &raw[..], &limits),
+            Err(ParseError::BodyTooLarge)
+        ));
+    }
+
+    #[test]
+    fn differing_content_lengths_are_rejected() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 10\r\n\r\nabcdefghij";
+        assert!(matches!(parse(raw), Err(ParseError::InvalidHeader(_))));
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 3, 10\r\n\r\nabcdefghij";
+        assert!(matches!(parse(raw), Err(ParseError::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn repeated_equal_content_lengths_are_accepted() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 3\r\ncontent-length: 3\r\n\r\nabc";
+        assert_eq!(parse(raw).unwrap().body, b"abc");
+    }
+
+    #[test]
+    fn repeated_transfer_encoding_is_rejected() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+                    Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert!(matches!(parse(raw), Err(ParseError::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn non_utf8_header_is_invalid() {
+        assert!(matches!(
+            parse(b"GET / HTTP/1.1\r\nX-Name: \xfe\xff\r\n\r\n"),
+            Err(ParseError::InvalidHeader(_))
+        ));
+    }
+}
@@ -0,0 +1,48 @@
+//! Identifiers that tie a response and its access log line to the request.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(1);
+
+/// A fresh ID: random bits so IDs from different runs don't collide,
+/// followed by a counter so IDs within one run never do.
+pub(crate) fn generate() -> String {
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    // Every `RandomState` is seeded differently, from OS randomness.
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(sequence);
+    format!("{:016x}-{sequence:08x}", hasher.finish())
+}
+
+/// Whether an incoming `X-Request-Id` is safe to reuse: short, and free of
+/// spaces and quotes that would garble the access log.
+pub(crate) fn is_valid(id: &str) -> bool {
+    (1..=128).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_graphic() && b != b'"')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_ids_are_unique_and_reusable() {
+        let first = generate();
+        let second = generate();
+        assert_ne!(first, second);
+        assert!(is_valid(&first), "{first}");
+        assert_eq!(first.len(), 25, "{first}");
+    }
+
+    #[test]
+    fn incoming_ids_must_be_short_and_log_safe() {
+        assert!(is_valid("abc-123"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("has space"));
+        assert!(!is_valid("quo\"te"));
+        assert!(!is_valid(&"x".repeat(129)));
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    time::SystemTime,
+};
+
+use crate::{httpdate::format_http_date, status::StatusCode, websocket::Upgraded};
+
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Replaces `body` when set, see `stream` and `chunked`.
+    stream: Option<BodyStream>,
+    /// Takes over the connection once this response is sent, see `upgrade`.
+    on_upgrade: Option<Box<dyn FnOnce(Upgraded) + Send>>,
+}
+
+enum BodyStream {
+    /// Exactly as many bytes as the `Content-Length` header says.
+    Sized(Box<dyn Read + Send>),
+    /// Read until it ends and sent with `Transfer-Encoding: chunked`.
+    Chunked(Box<dyn Read + Send>),
+    /// Read until it ends and sent as it is, the connection closing after
+    /// it to mark the end. See `unchunk`.
+    Unframed(Box<dyn Read + Send>),
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            stream: None,
+            on_upgrade: None,
+        }
+    }
+
+    /// A minimal built-in HTML page naming `status`, for errors that have no
+    /// page of their own.
+    pub fn error(status: StatusCode) -> Self {
+        let body = format!(
+            "<!DOCTYPE html>\n<html>\n<head><title>{status}</title></head>\n\
+             <body><h1>{status}</h1></body>\n</html>\n"
+        );
+        Response::new(status)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(body)
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Sends the next `len` bytes of `reader` as the body, copying them to
+    /// the connection in small chunks instead of holding them in memory.
+    pub fn stream(mut self, reader: impl Read + Send + 'static, len: u64) -> Self {
+        self.body = Vec::new();
+        self.stream = Some(BodyStream::Sized(Box::new(reader.take(len))));
+        self.remove_header("Content-Length");
+        self.header("Content-Length", len.to_string())
+    }
+
+    /// Sends everything `reader` produces as the body, for bodies whose
+    /// length isn't known up front. Each read becomes one chunk of a
+    /// `Transfer-Encoding: chunked` body.
+    pub fn chunked(mut self, reader: impl Read + Send + 'static) -> Self {
+        self.set_chunked(Box::new(reader));
+        self
+    }
+
+    pub(crate) fn set_chunked(&mut self, reader: Box<dyn Read + Send>) {
+        self.body = Vec::new();
+        self.stream = Some(BodyStream::Chunked(reader));
+        self.remove_header("Content-Length");
+        self.remove_header("Transfer-Encoding");
+        self.headers
+            .push(("Transfer-Encoding".into(), "chunked".into()));
+    }
+
+    /// Takes out a body set with `stream`, for re-encoding it; chunked
+    /// bodies, which may be produced bit by bit, stay where they are.
+    pub(crate) fn take_sized_stream(&mut self) -> Option<Box<dyn Read + Send>> {
+        match self.stream.take() {
+            Some(BodyStream::Sized(reader)) => Some(reader),
+            stream => {
+                self.stream = stream;
+                None
+            }
+        }
+    }
+
+    /// Turns a chunked body into one sent without framing, for HTTP/1.0
+    /// clients, which don't understand `Transfer-Encoding`. The connection
+    /// must be closed after such a body, as that's the only way the client
+    /// can tell where it ends. Returns whether the body was chunked.
+    pub(crate) fn unchunk(&mut self) -> bool {
+        match self.stream.take() {
+            Some(BodyStream::Chunked(reader)) => {
+                self.stream = Some(BodyStream::Unframed(reader));
+                self.remove_header("Transfer-Encoding");
+                true
+            }
+            stream => {
+                self.stream = stream;
+                false
+            }
+        }
+    }
+
+    /// Hands the connection to `on_upgrade` after this response is sent,
+    /// instead of reading further requests from it. Only honoured for
+    /// `101 Switching Protocols`.
+    pub fn upgrade<F>(mut self, on_upgrade: F) -> Self
+    where
+        F: FnOnce(Upgraded) + Send + 'static,
+    {
+        self.on_upgrade = Some(Box::new(on_upgrade));
+        self
+    }
+
+    pub(crate) fn take_upgrade(&mut self) -> Option<Box<dyn FnOnce(Upgraded) + Send>> {
+        if self.status == StatusCode::SWITCHING_PROTOCOLS {
+            self.on_upgrade.take()
+        } else {
+            None
+        }
+    }
+
+    /// Whether the body is read from a stream rather than held in `body`.
+    pub fn is_streamed(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Removes every header named `name`, ignoring case.
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Writes the status line, headers, a blank line and then the body.
+    ///
+    /// `Date` and a `Content-Length` matching the body are added unless the
+    /// response already carries them or, for `Content-Length`, its status
+    /// doesn't allow a body.
+    ///
+    /// Returns the number of body bytes written, not counting chunk framing.
+    /// A streamed body that ends before its `Content-Length` fails with
+    /// `UnexpectedEof`, since the connection can't be reused after a short
+    /// body.
+    pub fn write_to(&mut self, writer: &mut impl Write) -> io::Result<u64> {
+        writer.write_all(&self.serialize(true))?;
+        let expected = self
+            .header_value("Content-Length")
+            .and_then(|len| len.parse().ok())
+            .unwrap_or(0);
+        match &mut self.stream {
+            None => Ok(self.body.len() as u64),
+            Some(BodyStream::Sized(reader)) => {
+                let written = io::copy(reader, writer)?;
+                if written < expected {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "streamed body ended before its Content-Length",
+                    ));
+                }
+                Ok(written)
+            }
+            Some(BodyStream::Chunked(reader)) => write_chunks(reader, writer),
+            Some(BodyStream::Unframed(reader)) => copy_flushing(reader, writer),
+        }
+    }
+
+    /// Writes everything `write_to` would except the body, for answering
+    /// `HEAD` requests.
+    pub fn write_head_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.serialize(false))
+    }
+
+    /// 1xx, `204 No Content` and `304 Not Modified` responses never carry a
+    /// body, so they get no `Content-Length` either.
+    fn allows_body(&self) -> bool {
+        let code = self.status.as_u16();
+        !(100..200).contains(&code) && code != 204 && code != 304
+    }
+
+    fn serialize(&self, include_body: bool) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if self.header_value("Date").is_none() {
+            head.push_str(&format!(
+                "Date: {}\r\n",
+                format_http_date(SystemTime::now())
+            ));
+        }
+        let unsized_body = matches!(
+            self.stream,
+            Some(BodyStream::Chunked(_) | BodyStream::Unframed(_))
+        );
+        if self.header_value("Content-Length").is_none() && self.allows_body() && !unsized_body {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+
+        let mut bytes = head.into_bytes();
+        if include_body {
+            bytes.extend_from_slice(&self.body);
+        }
+        bytes
+    }
+}
+
+/// Writes each read from `reader` as a chunk, then the empty last chunk.
+fn write_chunks(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<u64> {
+    let mut buf = [0; 8 * 1024];
+    let mut written = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        write!(writer, "{n:x}\r\n")?;
+        writer.write_all(&buf[..n])?;
+        writer.write_all(b"\r\n")?;
+        // Chunks are sent as soon as they're produced rather than whenever
+        // a buffered writer fills up.
+        writer.flush()?;
+        written += n as u64;
+    }
+    writer.write_all(b"0\r\n\r\n")?;
+    writer.flush()?;
+    Ok(written)
+}
+
+/// Like `io::copy`, but flushes after every read so a body produced bit by
+/// bit, such as an event stream, reaches the client as it's produced.
+fn copy_flushing(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<u64> {
+    let mut buf = [0; 8 * 1024];
+    let mut written = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(written),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        writer.write_all(&buf[..n])?;
+        writer.flush()?;
+        written += n as u64;
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("body", &self.body)
+            .field("streamed", &self.is_streamed())
+            .field("upgrade", &self.on_upgrade.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_support::{dechunk, split_head};
+
+    #[test]
+    fn serializes_status_line_headers_and_body() {
+        let mut response = Response::new(StatusCode::OK)
+            .header("Content-Type", "text/plain")
+            .header("Date", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .body("hello");
+        let mut out = Vec::new();
+        assert_eq!(response.write_to(&mut out).unwrap(), 5);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/plain\r\n\
+             Date: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Content-Length: 5\r\n\
+             \r\n\
+             hello"
+        );
+    }
+
+    #[test]
+    fn bodiless_statuses_get_no_content_length() {
+        let mut out = Vec::new();
+        Response::new(StatusCode::NO_CONTENT)
+            .write_to(&mut out)
+            .unwrap();
+        let (head, body) = split_head(&out);
+        assert!(!head.contains("Content-Length"), "{head}");
+        assert!(body.is_empty());
+    }
+
+    /// Three reads, so three chunks.
+    fn fragments() -> impl Read + Send {
+        Cursor::new(b"one ".to_vec())
+            .chain(Cursor::new(b"two ".to_vec()))
+            .chain(Cursor::new(b"three".to_vec()))
+    }
+
+    #[test]
+    fn chunked_body_reassembles() {
+        let mut response = Response::new(StatusCode::OK).chunked(fragments());
+        let mut out = Vec::new();
+        assert_eq!(response.write_to(&mut out).unwrap(), 13);
+
+        let (head, body) = split_head(&out);
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!head.contains("Content-Length"));
+        assert!(body.starts_with(b"4\r\none \r\n4\r\ntwo \r\n5\r\nthree\r\n"));
+        assert_eq!(dechunk(body), b"one two three");
+    }
+
+    #[test]
+    fn unchunked_body_is_sent_without_framing() {
+        let mut response = Response::new(StatusCode::OK).chunked(fragments());
+        assert!(response.unchunk());
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        let (head, body) = split_head(&out);
+        assert!(!head.contains("Transfer-Encoding"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(body, b"one two three");
+    }
+
+    #[test]
+    fn only_chunked_bodies_are_unchunked() {
+        let mut response = Response::new(StatusCode::OK).body("fixed");
+        assert!(!response.unchunk());
+        let mut response = Response::new(StatusCode::OK).stream(fragments(), 13);
+        assert!(!response.unchunk());
+        assert_eq!(response.header_value("Content-Length"), Some("13"));
+    }
+}
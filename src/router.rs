@@ -0,0 +1,362 @@
+use crate::{
+    percent::percent_decode,
+    request::{Method, Request},
+    response::Response,
+    status::StatusCode,
+};
+
+pub type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync + 'static>;
+
+/// Wraps request handling: receives the request and `next`, which runs the
+/// rest of the chain, and may inspect or replace the response.
+pub type Middleware =
+    Box<dyn Fn(&Request, &dyn Fn(&Request) -> Response) -> Response + Send + Sync + 'static>;
+
+struct Route {
+    method: Method,
+    path: String,
+    handler: Handler,
+}
+
+/// Dispatches requests to handlers registered by method and path.
+///
+/// Paths are matched exactly, except that a `{name}` segment matches any
+/// non-empty segment and makes it available as `Request::path_param(name)`.
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Handler,
+    middleware: Vec<Middleware>,
+    ignore_trailing_slash: bool,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router {
+            routes: Vec::new(),
+            fallback: Box::new(not_found),
+            middleware: Vec::new(),
+            ignore_trailing_slash: true,
+        }
+    }
+
+    pub fn route<H>(&mut self, method: Method, path: &str, handler: H) -> &mut Self
+    where
+        H: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            path: path.to_string(),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Sets the handler used when no registered route matches.
+    pub fn fallback<H>(&mut self, handler: H) -> &mut Self
+    where
+        H: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.fallback = Box::new(handler);
+        self
+    }
+
+    /// Adds middleware around every request, including ones answered by the
+    /// fallback or with `405`. Middleware runs in registration order, so
+    /// the first one added is the outermost.
+    pub fn wrap<M>(&mut self, middleware: M) -> &mut Self
+    where
+        M: Fn(&Request, &dyn Fn(&Request) -> Response) -> Response + Send + Sync + 'static,
+    {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Runs the request through the middleware and then the handler
+    /// registered for its method and path.
+    pub fn dispatch(&self, request: &Request) -> Response {
+        self.run_from(0, request)
+    }
+
+    fn run_from(&self, index: usize, request: &Request) -> Response {
+        match self.middleware.get(index) {
+            Some(middleware) => middleware(request, &|request| self.run_from(index + 1, request)),
+            None => self.handle(request),
+        }
+    }
+
+    /// Runs the handler registered for the request's method and path.
+    /// `HEAD` requests fall back to the `GET` route for the same path.
+    ///
+    /// A path registered only under other methods gets a `405 Method Not
+    /// Allowed` listing them in `Allow`; unknown paths go to the fallback.
+    fn handle(&self, request: &Request) -> Response {
+        let found = match &request.method {
+            Method::Head => self
+                .find(&Method::Head, &request.path)
+                .or_else(|| self.find(&Method::Get, &request.path)),
+            method => self.find(method, &request.path),
+        };
+        if let Some((route, params)) = found {
+            if params.is_empty() {
+                return (route.handler)(request);
+            }
+            let mut request = request.clone();
+            request.path_params = params;
+            return (route.handler)(&request);
+        }
+
+        let allowed = self.allowed_methods(&request.path);
+        if allowed.is_empty() {
+            return (self.fallback)(request);
+        }
+        let allow = allowed
+            .iter()
+            .map(|method| method.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        Response::new(StatusCode::METHOD_NOT_ALLOWED).header("Allow", allow)
+    }
+
+    /// Finds the route for `method` and `path` along with the path
+    /// parameters it captured. Exact routes win over patterns that would
+    /// also match.
+    fn find(&self, method: &Method, path: &str) -> Option<(&Route, Vec<(String, String)>)> {
+        let candidates = || self.routes.iter().filter(|route| route.method == *method);
+        let matching = |route: &'_ Route| self.match_path(&route.path, path);
+        candidates()
+            .filter(|route| !is_pattern(&route.path))
+            .find_map(|route| matching(route).map(|params| (route, params)))
+            .or_else(|| {
+                candidates()
+                    .filter(|route| is_pattern(&route.path))
+                    .find_map(|route| matching(route).map(|params| (route, params)))
+            })
+    }
+
+    /// Methods with a route matching `path`, in registration order.
+    pub fn allowed_methods(&self, path: &str) -> Vec<&Method> {
+        let mut methods: Vec<&Method> = Vec::new();
+        for route in &self.routes {
+            if self.match_path(&route.path, path).is_some() && !methods.contains(&&route.method) {
+                methods.push(&route.method);
+            }
+        }
+        methods
+    }
+
+    /// Controls whether `/users/5/` matches a route for `/users/5` and vice
+    /// versa. On by default.
+    pub fn ignore_trailing_slash(&mut self, ignore: bool) -> &mut Self {
+        self.ignore_trailing_slash = ignore;
+        self
+    }
+
+    /// Matches `path` against a route path whose `{name}` segments capture
+    /// any non-empty segment, returning the decoded captures.
+    fn match_path(&self, pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        let (pattern, path) = if self.ignore_trailing_slash {
+            (trim_trailing_slash(pattern), trim_trailing_slash(path))
+        } else {
+            (pattern, path)
+        };
+        if !is_pattern(pattern) {
+            return (pattern == path).then(Vec::new);
+        }
+
+        let mut params = Vec::new();
+        let mut segments = path.split('/');
+        for expected in pattern.split('/') {
+            let segment = segments.next()?;
+            match expected
+                .strip_prefix('{')
+                .and_then(|name| name.strip_suffix('}'))
+            {
+                Some(name) => {
+                    if segment.is_empty() {
+                        return None;
+                    }
+                    params.push((name.to_string(), percent_decode(segment).ok()?));
+                }
+                None if expected == segment => {}
+                None => return None,
+            }
+        }
+        segments.next().is_none().then_some(params)
+    }
+}
+
+fn is_pattern(path: &str) -> bool {
+    path.contains('{')
+}
+
+fn trim_trailing_slash(path: &str) -> &str {
+    match path.strip_suffix('/') {
+        Some(trimmed) if !trimmed.is_empty() => trimmed,
+        _ => path,
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn not_found(_: &Request) -> Response {
+    Response::new(StatusCode::NOT_FOUND)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{method} {target} HTTP/1.1\r\n\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn text(response: &Response) -> &str {
+        std::str::from_utf8(&response.body).unwrap()
+    }
+
+    #[test]
+    fn dispatches_to_the_registered_route() {
+        let mut router = Router::new();
+        router
+            .route(Method::Get, "/health", |_| {
+                Response::new(StatusCode::OK).body("healthy")
+            })
+            .route(Method::Get, "/version", |_| {
+                Response::new(StatusCode::OK).body("1.0")
+            });
+
+        assert_eq!(
+            text(&router.dispatch(&request("GET", "/health"))),
+            "healthy"
+        );
+        assert_eq!(text(&router.dispatch(&request("GET", "/version"))), "1.0");
+        let response = router.dispatch(&request("GET", "/missing"));
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn other_methods_on_a_known_path_are_not_allowed() {
+        let mut router = Router::new();
+        router.route(Method::Get, "/", |_| Response::new(StatusCode::OK));
+        let response = router.dispatch(&request("POST", "/"));
+        assert_eq!(response.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.header_value("Allow"), Some("GET"));
+
+        router.route(Method::Delete, "/", |_| Response::new(StatusCode::OK));
+        let response = router.dispatch(&request("PUT", "/"));
+        assert_eq!(response.header_value("Allow"), Some("GET, DELETE"));
+    }
+
+    #[test]
+    fn head_falls_back_to_the_get_route() {
+        let mut router = Router::new();
+        router.route(Method::Get, "/", |_| Response::new(StatusCode::OK));
+        assert_eq!(
+            router.dispatch(&request("HEAD", "/")).status,
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn middleware_wraps_every_response() {
+        let mut router = Router::new();
+        router
+            .route(Method::Get, "/", |_| Response::new(StatusCode::OK))
+            .wrap(|request, next| {
+                let started = std::time::Instant::now();
+                let response = next(request);
+                let elapsed = started.elapsed().as_millis();
+                response.header("X-Elapsed-Ms", elapsed.to_string())
+            });
+
+        let response = router.dispatch(&request("GET", "/"));
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(response.header_value("X-Elapsed-Ms").is_some());
+        let response = router.dispatch(&request("GET", "/missing"));
+        assert!(response.header_value("X-Elapsed-Ms").is_some());
+    }
+
+    #[test]
+    fn middleware_runs_in_registration_order() {
+        let mut router = Router::new();
+        router.route(Method::Get, "/", |_| Response::new(StatusCode::OK));
+        for name in ["outer", "inner"] {
+            router.wrap(move |request, next| next(request).header("X-Order", name));
+        }
+        let response = router.dispatch(&request("GET", "/"));
+        let order: Vec<_> = response
+            .headers
+            .iter()
+            .filter(|(name, _)| name == "X-Order")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        // The inner middleware sees the response first.
+        assert_eq!(order, ["inner", "outer"]);
+    }
+
+    #[test]
+    fn middleware_can_answer_without_calling_next() {
+        let mut router = Router::new();
+        router
+            .route(Method::Get, "/", |_| Response::new(StatusCode::OK))
+            .wrap(|_, _| Response::new(StatusCode::UNAUTHORIZED));
+        assert_eq!(
+            router.dispatch(&request("GET", "/")).status,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    fn user_router() -> Router {
+        let mut router = Router::new();
+        router
+            .route(Method::Get, "/users/{id}", |request| {
+                Response::new(StatusCode::OK).body(request.path_param("id").unwrap_or("?"))
+            })
+            .route(Method::Get, "/users/me", |_| {
+                Response::new(StatusCode::OK).body("me")
+            });
+        router
+    }
+
+    #[test]
+    fn path_parameters_are_captured() {
+        let router = user_router();
+        assert_eq!(text(&router.dispatch(&request("GET", "/users/42"))), "42");
+        assert_eq!(
+            text(&router.dispatch(&request("GET", "/users/a%20b"))),
+            "a b"
+        );
+        assert_eq!(text(&router.dispatch(&request("GET", "/users/42/"))), "42");
+        for path in ["/users/", "/users", "/users/42/posts"] {
+            let response = router.dispatch(&request("GET", path));
+            assert_eq!(response.status, StatusCode::NOT_FOUND, "{path}");
+        }
+    }
+
+    #[test]
+    fn exact_routes_win_over_patterns() {
+        let router = user_router();
+        assert_eq!(text(&router.dispatch(&request("GET", "/users/me"))), "me");
+    }
+
+    #[test]
+    fn trailing_slashes_can_be_significant() {
+        let mut router = user_router();
+        router.ignore_trailing_slash(false);
+        let response = router.dispatch(&request("GET", "/users/42/"));
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn custom_fallback_answers_unknown_paths() {
+        let mut router = Router::new();
+        router.fallback(|_| Response::new(StatusCode::from_u16(410)));
+        let response = router.dispatch(&request("GET", "/missing"));
+        assert_eq!(response.status.as_u16(), 410);
+    }
+}
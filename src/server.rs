@@ -0,0 +1,2981 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, BufRead, BufReader, ErrorKind, Read},
+    net::{IpAddr, SocketAddr},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, PoisonError, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
+use crate::{
+    access_log::{AccessLog, AccessLogger},
+    auth::{constant_time_eq, BasicAuth},
+    compress::gzip_response,
+    cors::Cors,
+    file_cache::FileCache,
+    files::{self, serve_path, serve_path_with_options, NotFoundHandler, ServeOptions},
+    listener::{self, Connection, ListenOptions, Listener, Stream},
+    log::{self, Level as LogLevel},
+    metrics::Metrics,
+    pool::{panic_message, PoolError, ThreadPool},
+    proxy::ProxyHandler,
+    rate_limit::{RateLimit, RateLimiter},
+    request::{Method, ParseError, ParseLimits, Request},
+    request_id,
+    response::Response,
+    router::Router,
+    status::StatusCode,
+    websocket::Upgraded,
+};
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// IPv4 or IPv6. `[::]` listens on both where the platform supports
+    /// dual-stack sockets.
+    pub addr: SocketAddr,
+    /// Further addresses to listen on alongside `addr`, all served by the
+    /// same pool. Ignored when listening on a Unix socket.
+    pub extra_addrs: Vec<SocketAddr>,
+    /// Listen on this Unix domain socket instead of `addr`.
+    #[cfg(unix)]
+    pub unix_socket: Option<PathBuf>,
+    /// Connections the OS queues for `accept` before refusing new ones.
+    /// Capped by the system limit, `net.core.somaxconn` on Linux.
+    pub backlog: u32,
+    /// Sets `SO_REUSEADDR` so a restarted server can rebind its port right
+    /// away. Only honoured on Unix.
+    pub reuse_address: bool,
+    pub pool_size: usize,
+    /// Maximum number of accepted connections waiting for a free worker.
+    /// `None` leaves the queue unbounded.
+    pub queue_capacity: Option<usize>,
+    /// Connections served or queued at once. While the limit is reached
+    /// the server stops accepting, leaving new connections waiting in the
+    /// listen backlog. `None` means no limit.
+    pub max_connections: Option<usize>,
+    pub document_root: PathBuf,
+    /// List the contents of directories that have no `index.html`. When
+    /// off, such directories are answered with `403 Forbidden`.
+    pub directory_listing: bool,
+    /// Serve the root `index.html` for unknown paths, for single-page
+    /// apps; see `ServeOptions::spa_fallback`.
+    pub spa_fallback: bool,
+    /// Paths left out of the SPA fallback, such as `/api`.
+    pub spa_api_prefixes: Vec<String>,
+    /// Answers requests the default router has no route or file for, in
+    /// place of `404.html`.
+    pub not_found_handler: Option<NotFoundHandler>,
+    /// Applied to every accepted connection; a client that stays silent for
+    /// longer has its connection closed.
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    /// Disables Nagle's algorithm on accepted connections so small
+    /// responses go out without waiting for more data.
+    pub tcp_nodelay: bool,
+    /// How long a persistent connection may sit idle between requests before
+    /// it's closed. Must be non-zero.
+    pub keep_alive_timeout: Duration,
+    /// Requests served on one connection before it's closed, making the
+    /// client reconnect. `None` means no limit.
+    pub keep_alive_max_requests: Option<usize>,
+    /// Longest request line or header line accepted; longer ones get a `431`.
+    pub max_line_bytes: usize,
+    /// Largest header section accepted; larger ones get a `431`.
+    pub max_header_bytes: usize,
+    /// Most headers accepted in one request; more get a `431`.
+    pub max_headers: usize,
+    /// Largest request body accepted; larger ones get a `413`.
+    pub max_body_bytes: usize,
+    /// Overrides the level taken from `RUST_LOG` (which defaults to `info`).
+    pub log_level: Option<LogLevel>,
+    pub access_log: AccessLog,
+    /// Gzip text responses for clients that send `Accept-Encoding: gzip`.
+    pub compress: bool,
+    /// Bodies smaller than this are sent uncompressed.
+    pub compress_min_bytes: usize,
+    /// Clients that exceed this, keyed by IP address, get a `429`. Unix
+    /// socket clients have no address and are never limited. Binding fails
+    /// when the rate isn't positive or the burst is zero.
+    pub rate_limit: Option<RateLimit>,
+    /// Reverse proxies whose `X-Forwarded-For` is believed. Requests they
+    /// forward are rate limited and logged by the client address the
+    /// header gives; everyone else's `X-Forwarded-For` is ignored.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Serve HTTPS with this certificate instead of plain HTTP. Has no
+    /// effect on a Unix socket.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+    /// Answer cross-origin requests from the origins it allows.
+    pub cors: Option<Cors>,
+    /// Require these credentials for the paths they cover.
+    pub basic_auth: Option<BasicAuth>,
+    /// Forward the paths it covers to an upstream server.
+    pub proxy: Option<ProxyHandler>,
+    /// Handlers that take longer get a `503` sent in their place. They keep
+    /// running in the background, since threads can't be cancelled, and
+    /// their eventual response is discarded. `None` lets handlers run for
+    /// as long as they like.
+    pub request_timeout: Option<Duration>,
+    /// How long shutdown waits for in-flight connections to finish before
+    /// abandoning them. New connections are answered with `503` meanwhile.
+    pub shutdown_grace_period: Duration,
+    /// Sent as the `Server` header on every response, unless the handler
+    /// set one. Empty leaves it out.
+    pub server_name: String,
+    /// Adds an `X-Response-Time` header giving how long the request took,
+    /// from the end of its headers to the start of the response, in
+    /// milliseconds.
+    pub response_timing: bool,
+    /// HTML pages to send in place of the built-in ones, by status code.
+    /// They replace only bodies the server made up itself: the built-in
+    /// error page or an empty body. Pages that can't be read fall back to
+    /// the built-in one.
+    pub error_pages: HashMap<u16, PathBuf>,
+    /// Memory the default router may spend keeping small files from the
+    /// document root cached, least recently used first out. `0` turns the
+    /// cache off.
+    pub cache_capacity_bytes: usize,
+    /// Enables `POST /admin/shutdown` for requests that send this token as
+    /// `Authorization: Bearer <token>`, which starts a graceful shutdown as
+    /// if a signal had arrived. `None` leaves the route out.
+    pub admin_token: Option<String>,
+    /// Answer `TRACE` by echoing the request back, minus credentials.
+    /// Off by default, as echoing requests is a known way to leak headers
+    /// to scripts; `TRACE` then gets a `405`.
+    pub allow_trace: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: SocketAddr::from(([0, 0, 0, 0], 7878)),
+            extra_addrs: Vec::new(),
+            #[cfg(unix)]
+            unix_socket: None,
+            backlog: 128,
+            reuse_address: true,
+            pool_size: 10,
+            queue_capacity: None,
+            max_connections: None,
+            document_root: PathBuf::from("public"),
+            directory_listing: false,
+            spa_fallback: false,
+            spa_api_prefixes: Vec::new(),
+            not_found_handler: None,
+            read_timeout: Some(Duration::from_secs(5)),
+            write_timeout: Some(Duration::from_secs(5)),
+            tcp_nodelay: true,
+            keep_alive_timeout: Duration::from_secs(5),
+            keep_alive_max_requests: None,
+            max_line_bytes: ParseLimits::default().max_line_bytes,
+            max_header_bytes: ParseLimits::default().max_header_bytes,
+            max_headers: ParseLimits::default().max_headers,
+            max_body_bytes: ParseLimits::default().max_body_bytes,
+            log_level: None,
+            access_log: AccessLog::Stdout,
+            compress: false,
+            compress_min_bytes: 1024,
+            rate_limit: None,
+            trusted_proxies: Vec::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            cors: None,
+            basic_auth: None,
+            proxy: None,
+            request_timeout: None,
+            shutdown_grace_period: Duration::from_secs(30),
+            server_name: format!("rust-toy-webserver/{}", env!("CARGO_PKG_VERSION")),
+            response_timing: false,
+            error_pages: HashMap::new(),
+            cache_capacity_bytes: 0,
+            admin_token: None,
+            allow_trace: false,
+        }
+    }
+}
+
+/// The document root as of the latest `ServerHandle::reload`. Handlers
+/// clone the inner `Arc` per request, so a reload never changes the root
+/// under a request that's already being served.
+type DocumentRoot = Arc<RwLock<Arc<PathBuf>>>;
+
+fn current_root(document_root: &DocumentRoot) -> Arc<PathBuf> {
+    document_root
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Builds the router with the built-in routes, falling back to files from
+/// the document root for any other path.
+pub fn default_router(config: &ServerConfig) -> Router {
+    let document_root = Arc::new(RwLock::new(Arc::new(config.document_root.clone())));
+    router_with_root(config, document_root)
+}
+
+fn router_with_root(config: &ServerConfig, document_root: DocumentRoot) -> Router {
+    let mut router = Router::new();
+
+    // Liveness probes shouldn't fail because of unrelated disk trouble, so
+    // this one never touches the filesystem.
+    router.route(Method::Get, "/healthz", |_| {
+        Response::new(StatusCode::OK)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body("ok")
+    });
+
+    router.route(Method::Post, "/echo", |request| {
+        let content_type = request
+            .header("Content-Type")
+            .unwrap_or("application/octet-stream");
+        Response::new(StatusCode::OK)
+            .header("Content-Type", content_type)
+            .body(request.body.clone())
+    });
+
+    {
+        let document_root = document_root.clone();
+        router.route(Method::Get, "/sleep", move |request| {
+            let root = current_root(&document_root);
+            thread::sleep(sleep_duration(request));
+            serve_path(&root, "/sleep.html", request)
+        });
+    }
+    let options = ServeOptions {
+        directory_listing: config.directory_listing,
+        cache: (config.cache_capacity_bytes > 0)
+            .then(|| Arc::new(FileCache::new(config.cache_capacity_bytes))),
+        spa_fallback: config.spa_fallback,
+        spa_api_prefixes: config.spa_api_prefixes.clone(),
+        not_found: config.not_found_handler.clone(),
+    };
+    router.fallback(move |request| {
+        let root = current_root(&document_root);
+        match request.method {
+            Method::Get | Method::Head => {
+                serve_path_with_options(&root, &request.path, request, &options)
+            }
+            _ => match &options.not_found {
+                Some(handler) => handler.handle(request),
+                None => files::not_found(&root, false),
+            },
+        }
+    });
+
+    // CORS goes first so preflight requests, which browsers send without
+    // credentials, are answered before authentication is checked.
+    if let Some(cors) = &config.cors {
+        router.wrap(cors.clone().middleware());
+    }
+    if let Some(auth) = &config.basic_auth {
+        router.wrap(auth.clone().middleware());
+    }
+    if let Some(proxy) = &config.proxy {
+        router.wrap(proxy.clone().middleware());
+    }
+
+    router
+}
+
+const DEFAULT_SLEEP: Duration = Duration::from_secs(5);
+const MAX_SLEEP: Duration = Duration::from_secs(10);
+
+/// Reads the `/sleep` delay from an `ms` query parameter, clamped to
+/// `MAX_SLEEP`, defaulting to `DEFAULT_SLEEP` when absent or unparseable.
+fn sleep_duration(request: &Request) -> Duration {
+    request
+        .query_param("ms")
+        .and_then(|ms| ms.parse().ok())
+        .map_or(DEFAULT_SLEEP, |ms| Duration::from_millis(ms).min(MAX_SLEEP))
+}
+
+/// Binds the configured address and serves connections until the process
+/// receives Ctrl+C or, on Unix, `SIGTERM`.
+pub fn run_server(config: ServerConfig) -> io::Result<()> {
+    serve_until_signal(Server::bind(config)?)
+}
+
+/// Like `run_server`, but dispatches requests through a caller-supplied router.
+pub fn run_server_with_router(config: ServerConfig, router: Router) -> io::Result<()> {
+    serve_until_signal(Server::bind_with_router(config, router)?)
+}
+
+fn serve_until_signal(server: Server) -> io::Result<()> {
+    let should_exit = server.should_exit.clone();
+    // With the `termination` feature, ctrlc also catches SIGTERM and SIGHUP
+    // on Unix, which is how orchestrators ask a server to stop. Installed
+    // before accepting, so a signal sent once the server is up isn't missed.
+    if let Err(err) = ctrlc::set_handler(move || should_exit.store(true, Ordering::SeqCst)) {
+        log::warn!("Failed to set shutdown signal handler: {err}");
+    }
+    let forced = server.run().wait();
+    if forced > 0 {
+        log::warn!("Force-closed {forced} connections after the shutdown grace period");
+    }
+    Ok(())
+}
+
+/// A server whose listener is bound but which isn't accepting connections
+/// yet, so the bound address can be read before `run` is called.
+pub struct Server {
+    listeners: Vec<Listener>,
+    pool: ThreadPool,
+    state: Arc<ServerState>,
+    /// Created at bind time so the admin shutdown route can set it.
+    should_exit: Arc<AtomicBool>,
+}
+
+impl Server {
+    /// Binds `config.addr`, and any `extra_addrs`, and sets up the default
+    /// router. Port 0 asks the OS for a free port; `local_addr` reports the
+    /// one it picked.
+    ///
+    /// Fails before binding when the document root isn't a directory.
+    pub fn bind(config: ServerConfig) -> io::Result<Server> {
+        check_document_root(&config.document_root)?;
+        let document_root = Arc::new(RwLock::new(Arc::new(config.document_root.clone())));
+        let router = router_with_root(&config, document_root.clone());
+        Self::bind_inner(config, router, document_root)
+    }
+
+    /// Like `bind`, but dispatches requests through a caller-supplied router.
+    ///
+    /// `GET /metrics`, and `POST /admin/shutdown` when an admin token is
+    /// configured, are added to the router unless it already handles those
+    /// paths.
+    pub fn bind_with_router(config: ServerConfig, router: Router) -> io::Result<Server> {
+        let document_root = Arc::new(RwLock::new(Arc::new(config.document_root.clone())));
+        Self::bind_inner(config, router, document_root)
+    }
+
+    fn bind_inner(
+        config: ServerConfig,
+        mut router: Router,
+        document_root: DocumentRoot,
+    ) -> io::Result<Server> {
+        log::init(config.log_level);
+        if let Some(limit) = &config.rate_limit {
+            limit
+                .validate()
+                .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+        }
+        let options = ListenOptions {
+            backlog: config.backlog,
+            reuse_address: config.reuse_address,
+        };
+        #[cfg(unix)]
+        let listeners = match &config.unix_socket {
+            Some(path) => vec![Listener::bind_unix(path)?],
+            None => bind_all(&config, &options)?,
+        };
+        #[cfg(not(unix))]
+        let listeners = bind_all(&config, &options)?;
+        for listener in &listeners {
+            listener.set_nonblocking(true).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to make listener non-blocking: {err}"),
+                )
+            })?;
+        }
+        let pool = ThreadPool::with_queue_capacity(config.pool_size, config.queue_capacity)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+
+        let metrics = Arc::new(Metrics::new());
+        if router.allowed_methods("/metrics").is_empty() {
+            let metrics = metrics.clone();
+            let queue = pool.queue_monitor();
+            router.route(Method::Get, "/metrics", move |_| {
+                Response::new(StatusCode::OK)
+                    .header("Content-Type", "text/plain; version=0.0.4")
+                    .body(metrics.render(&queue.stats()))
+            });
+        }
+        let should_exit = Arc::new(AtomicBool::new(false));
+        if let Some(token) = &config.admin_token {
+            if router.allowed_methods("/admin/shutdown").is_empty() {
+                let token = token.clone();
+                let should_exit = should_exit.clone();
+                router.route(Method::Post, "/admin/shutdown", move |request| {
+                    admin_shutdown(request, &token, &should_exit)
+                });
+            }
+        }
+        let state = Arc::new(ServerState::new(config, router, document_root, metrics)?);
+        Ok(Server {
+            listeners,
+            pool,
+            state,
+            should_exit,
+        })
+    }
+
+    /// The TCP address bound for `addr`; fails when listening on a Unix socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    /// Every bound TCP address, `addr`'s first, or none when listening on a
+    /// Unix socket.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+
+    /// Starts accepting connections on a background thread.
+    ///
+    /// The server runs until `ServerHandle::shutdown` is called. Dropping
+    /// the handle instead leaves it running detached.
+    pub fn run(self) -> ServerHandle {
+        let local_addrs = self.local_addrs();
+        let should_exit = self.should_exit.clone();
+        let state = self.state.clone();
+        let (resize_tx, resize_rx) = mpsc::channel();
+        let thread = {
+            let should_exit = should_exit.clone();
+            thread::spawn(move || self.accept_loop(&should_exit, &resize_rx))
+        };
+        ServerHandle {
+            local_addrs,
+            should_exit,
+            state,
+            resize_tx,
+            thread,
+        }
+    }
+
+    fn accept_loop(self, should_exit: &AtomicBool, resize: &mpsc::Receiver<usize>) -> usize {
+        let Server {
+            listeners,
+            mut pool,
+            state,
+            ..
+        } = self;
+        for listener in &listeners {
+            log::info!("Server started on {listener}");
+        }
+
+        loop {
+            for size in resize.try_iter() {
+                match pool.resize(size) {
+                    Ok(()) => log::info!("Resized thread pool to {size} workers"),
+                    Err(err) => log::error!("Failed to resize thread pool: {err}"),
+                }
+            }
+            let mut accepted = false;
+            let mut full = false;
+            // One accept per listener per pass, so a busy address can't
+            // starve the others.
+            for listener in &listeners {
+                let Some(slot) = ConnectionSlot::acquire(&state) else {
+                    full = true;
+                    break;
+                };
+                match listener.accept() {
+                    Ok(stream) => {
+                        log::debug!("Incoming connection accepted");
+                        accepted = true;
+                        dispatch(stream, slot, &pool, &state);
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    Err(err) => {
+                        log::error!("Failed connection {err}");
+                    }
+                }
+            }
+            if accepted {
+                continue;
+            }
+            if should_exit.load(Ordering::SeqCst) {
+                log::info!("Shutting down...");
+                break;
+            }
+            if full {
+                thread::sleep(Duration::from_millis(1));
+            } else {
+                thread::yield_now();
+            }
+        }
+        drain(&listeners, pool, &state)
+    }
+}
+
+/// Answers `POST /admin/shutdown`: a request bearing `token` starts the
+/// shutdown and gets `202 Accepted`, any other a `401`.
+fn admin_shutdown(request: &Request, token: &str, should_exit: &AtomicBool) -> Response {
+    let presented = request
+        .header("Authorization")
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+        .map(|(_, presented)| presented.trim());
+    if !presented.is_some_and(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes()))
+    {
+        return Response::error(StatusCode::UNAUTHORIZED).header("WWW-Authenticate", "Bearer");
+    }
+    log::info!("Shutdown requested through the admin endpoint");
+    should_exit.store(true, Ordering::SeqCst);
+    Response::new(StatusCode::ACCEPTED)
+}
+
+fn check_document_root(root: &Path) -> io::Result<()> {
+    let metadata = fs::metadata(root).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("document root {}: {err}", root.display()),
+        )
+    })?;
+    if !metadata.is_dir() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("document root {} is not a directory", root.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Binds `addr` followed by each of `extra_addrs`.
+fn bind_all(config: &ServerConfig, options: &ListenOptions) -> io::Result<Vec<Listener>> {
+    std::iter::once(config.addr)
+        .chain(config.extra_addrs.iter().copied())
+        .map(|addr| listener::bind_tcp(addr, options))
+        .collect()
+}
+
+/// Applies the configured timeouts and `TCP_NODELAY` to an accepted
+/// connection. Only failing to set a timeout is an error.
+fn configure_stream(stream: &Stream, config: &ServerConfig) -> io::Result<()> {
+    stream.set_read_timeout(config.read_timeout)?;
+    stream.set_write_timeout(config.write_timeout)?;
+    if let Err(err) = stream.set_nodelay(config.tcp_nodelay) {
+        log::warn!("Failed to set TCP_NODELAY: {err}");
+    }
+    Ok(())
+}
+
+/// Applies the connection settings to an accepted stream and queues it
+/// for a worker, which holds on to `slot` until the connection closes.
+fn dispatch(stream: Stream, slot: ConnectionSlot, pool: &ThreadPool, state: &Arc<ServerState>) {
+    if let Err(err) = configure_stream(&stream, &state.config) {
+        log::error!("Failed to set connection timeouts: {err}");
+        return;
+    }
+    #[cfg(feature = "tls")]
+    let stream = match &state.tls {
+        Some(tls) => match stream.into_tls(tls) {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::error!("Failed to set up TLS: {err}");
+                return;
+            }
+        },
+        None => stream,
+    };
+    let state = state.clone();
+    let job = move || {
+        let _slot = slot;
+        match serve_stream(stream, &state) {
+            Ok(()) => {}
+            Err(err @ (ConnectionError::Timeout | ConnectionError::Parse(_))) => {
+                log::debug!("Connection closed: {err}");
+            }
+            Err(err) => log::warn!("Connection failed: {err}"),
+        }
+    };
+    if let Err(err) = pool.execute(job) {
+        log::error!("Failed to dispatch connection {err}");
+    }
+}
+
+/// Gives in-flight connections up to the grace period to finish, turning
+/// away new ones with `503`, then shuts the pool down.
+///
+/// Returns the number of connections still open when time ran out.
+fn drain(listeners: &[Listener], pool: ThreadPool, state: &Arc<ServerState>) -> usize {
+    state.draining.store(true, Ordering::SeqCst);
+    let deadline = Instant::now() + state.config.shutdown_grace_period;
+    while state.open_connections.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        let mut rejected = false;
+        for listener in listeners {
+            match listener.accept() {
+                Ok(stream) => {
+                    rejected = true;
+                    reject_draining(stream, state);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => log::error!("Failed connection {err}"),
+            }
+        }
+        if !rejected {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    pool.shutdown(deadline.saturating_duration_since(Instant::now()))
+}
+
+fn reject_draining(stream: Stream, state: &ServerState) {
+    log::debug!("Rejecting connection while shutting down");
+    #[cfg(feature = "tls")]
+    if state.tls.is_some() {
+        // Answering would take a handshake; closing says as much.
+        return;
+    }
+    let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+    let mut response = with_error_page(
+        Response::error(StatusCode::SERVICE_UNAVAILABLE),
+        &state.config,
+    )
+    .header("Connection", "close");
+    if response.write_to(&mut &stream).is_ok() {
+        state.metrics.record_response(response.status);
+        linger(&stream);
+    }
+}
+
+/// Reads and discards what the client sent, briefly, before a connection
+/// whose request was never read is closed. Closing with unread data makes
+/// the kernel reset the connection, which can destroy the response before
+/// the client reads it.
+fn linger(stream: &Stream) {
+    const LINGER: Duration = Duration::from_millis(100);
+    if stream.shutdown_write().is_err() {
+        return;
+    }
+    let deadline = Instant::now() + LINGER;
+    let mut reader = stream;
+    let mut buf = [0; 1024];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if remaining.is_zero() || stream.set_read_timeout(Some(remaining)).is_err() {
+            return;
+        }
+        match reader.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Why `ServerHandle::reload` couldn't apply a new configuration. Nothing
+/// is changed when it fails.
+#[derive(Debug)]
+pub enum ReloadError {
+    /// The named setting only takes effect on a freshly bound listener, so
+    /// changing it takes a full restart.
+    RestartRequired(&'static str),
+    /// The new document root is missing or isn't a directory.
+    DocumentRoot(io::Error),
+    Pool(PoolError),
+    /// The server has already shut down.
+    Stopped,
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReloadError::RestartRequired(setting) => {
+                write!(f, "changing {setting} requires a full restart")
+            }
+            ReloadError::DocumentRoot(err) => write!(f, "invalid {err}"),
+            ReloadError::Pool(err) => write!(f, "invalid pool size: {err}"),
+            ReloadError::Stopped => write!(f, "server has shut down"),
+        }
+    }
+}
+
+impl std::error::Error for ReloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReloadError::DocumentRoot(err) => Some(err),
+            ReloadError::Pool(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Controls a server started with `Server::run`.
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    should_exit: Arc<AtomicBool>,
+    state: Arc<ServerState>,
+    resize_tx: mpsc::Sender<usize>,
+    thread: JoinHandle<usize>,
+}
+
+impl ServerHandle {
+    /// The TCP address bound for `addr`, or `None` when listening on a Unix
+    /// socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs.first().copied()
+    }
+
+    /// Every bound TCP address, as `Server::local_addrs` reports them.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Applies `new_config`'s document root and pool size without closing
+    /// any connections.
+    ///
+    /// The new root is used from the next request on; requests already
+    /// being served finish with the old one. Custom routers, which don't
+    /// read the document root from the server, are unaffected. Shrinking
+    /// takes effect as the excess workers finish their current connection,
+    /// while the server keeps accepting. The new root is checked the same
+    /// way `bind` checks it. Every other setting keeps its value from
+    /// `bind`, and the listener settings (`addr`, `extra_addrs`,
+    /// `unix_socket`, `backlog`, `reuse_address` and `tls`) can't change
+    /// without a restart.
+    pub fn reload(&self, new_config: ServerConfig) -> Result<(), ReloadError> {
+        let config = &self.state.config;
+        if new_config.addr != config.addr {
+            return Err(ReloadError::RestartRequired("addr"));
+        }
+        if new_config.extra_addrs != config.extra_addrs {
+            return Err(ReloadError::RestartRequired("extra_addrs"));
+        }
+        #[cfg(unix)]
+        if new_config.unix_socket != config.unix_socket {
+            return Err(ReloadError::RestartRequired("unix_socket"));
+        }
+        if new_config.backlog != config.backlog {
+            return Err(ReloadError::RestartRequired("backlog"));
+        }
+        if new_config.reuse_address != config.reuse_address {
+            return Err(ReloadError::RestartRequired("reuse_address"));
+        }
+        #[cfg(feature = "tls")]
+        if new_config.tls != config.tls {
+            return Err(ReloadError::RestartRequired("tls"));
+        }
+        if new_config.pool_size == 0 {
+            return Err(ReloadError::Pool(PoolError::ZeroSize));
+        }
+        check_document_root(&new_config.document_root).map_err(ReloadError::DocumentRoot)?;
+        self.resize_tx
+            .send(new_config.pool_size)
+            .map_err(|_| ReloadError::Stopped)?;
+        *self
+            .state
+            .document_root
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new(new_config.document_root);
+        log::info!("Reloaded configuration");
+        Ok(())
+    }
+
+    /// Stops accepting connections and waits for the in-flight ones to be
+    /// served and the workers to exit, for at most the configured grace
+    /// period.
+    ///
+    /// Returns the number of connections that were abandoned because they
+    /// were still open when the grace period ran out.
+    pub fn shutdown(self) -> usize {
+        self.should_exit.store(true, Ordering::SeqCst);
+        self.wait()
+    }
+
+    /// Blocks until the server stops, which only happens once shutdown has
+    /// been requested. Returns what `shutdown` does.
+    pub fn wait(self) -> usize {
+        self.thread.join().unwrap_or_else(|_| {
+            log::error!("Server accept loop panicked");
+            0
+        })
+    }
+}
+
+/// Everything connection handlers share across the worker threads.
+struct ServerState {
+    config: ServerConfig,
+    router: Router,
+    access_log: Option<AccessLogger>,
+    metrics: Arc<Metrics>,
+    rate_limiter: Option<RateLimiter>,
+    /// Connections accepted and not yet finished, see `max_connections`.
+    open_connections: AtomicUsize,
+    /// Set once shutdown starts, so kept-alive connections close after the
+    /// response in progress.
+    draining: AtomicBool,
+    /// Shared with the default router's file routes, see `ServerHandle::reload`.
+    document_root: DocumentRoot,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl ServerState {
+    /// Everything but the listeners and the pool, which `handle_connection`
+    /// doesn't need, so connections can also be served without binding.
+    fn new(
+        config: ServerConfig,
+        router: Router,
+        document_root: DocumentRoot,
+        metrics: Arc<Metrics>,
+    ) -> io::Result<ServerState> {
+        Ok(ServerState {
+            #[cfg(feature = "tls")]
+            tls: config.tls.as_ref().map(TlsConfig::load).transpose()?,
+            rate_limiter: config.rate_limit.map(RateLimiter::new),
+            access_log: AccessLogger::open(&config.access_log)?,
+            open_connections: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+            document_root,
+            config,
+            router,
+            metrics,
+        })
+    }
+}
+
+/// Counts a connection against `max_connections` until dropped.
+struct ConnectionSlot(Arc<ServerState>);
+
+impl ConnectionSlot {
+    fn acquire(state: &Arc<ServerState>) -> Option<ConnectionSlot> {
+        let max = state.config.max_connections.unwrap_or(usize::MAX);
+        state
+            .open_connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < max).then_some(open + 1)
+            })
+            .ok()?;
+        Some(ConnectionSlot(state.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Why `handle_connection` stopped serving a connection early.
+#[derive(Debug)]
+pub enum ConnectionError {
+    Io(io::Error),
+    /// The client sent a request that couldn't be parsed. It has already
+    /// been answered with a 4xx status where one applies.
+    Parse(ParseError),
+    /// A read or write took longer than the configured timeout.
+    Timeout,
+    /// The TLS handshake failed, before any request was read.
+    Handshake(io::Error),
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::Io(err) => write!(f, "io error: {err}"),
+            ConnectionError::Parse(err) => write!(f, "bad request: {err}"),
+            ConnectionError::Timeout => write!(f, "connection timed out"),
+            ConnectionError::Handshake(err) => write!(f, "TLS handshake failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectionError::Io(err) => Some(err),
+            ConnectionError::Parse(err) => Some(err),
+            ConnectionError::Timeout => None,
+            ConnectionError::Handshake(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(err: io::Error) -> Self {
+        if is_timeout(&err) {
+            ConnectionError::Timeout
+        } else {
+            ConnectionError::Io(err)
+        }
+    }
+}
+
+impl From<ParseError> for ConnectionError {
+    fn from(err: ParseError) -> Self {
+        match err {
+            ParseError::Io(err) => err.into(),
+            err => ConnectionError::Parse(err),
+        }
+    }
+}
+
+/// Finishes setting up an accepted connection, then serves it.
+fn serve_stream(stream: Stream, state: &Arc<ServerState>) -> Result<(), ConnectionError> {
+    let remote_addr = stream.peer_addr();
+    stream.handshake().map_err(ConnectionError::Handshake)?;
+    handle_connection(stream, remote_addr, state)
+}
+
+/// Serves requests from `stream` until the client closes the connection,
+/// asks for it to be closed, or stays idle for longer than the keep-alive
+/// timeout, all of which count as success.
+///
+/// `remote_addr` is the client's address where it has one, for rate
+/// limiting and the access log.
+fn handle_connection(
+    stream: impl Connection,
+    remote_addr: Option<SocketAddr>,
+    state: &Arc<ServerState>,
+) -> Result<(), ConnectionError> {
+    let _active = state.metrics.connection();
+    // One reader for the life of the connection, so whatever a pipelining
+    // client sent past the current request stays buffered for the next.
+    // Requests are answered one at a time, which keeps responses in order.
+    let mut reader = BufReader::new(stream);
+    let limits = ParseLimits {
+        max_line_bytes: state.config.max_line_bytes,
+        max_header_bytes: state.config.max_header_bytes,
+        max_headers: state.config.max_headers,
+        max_body_bytes: state.config.max_body_bytes,
+    };
+    let mut served = 0;
+    loop {
+        if served > 0 && !wait_for_request(&mut reader, &state.config)? {
+            return Ok(());
+        }
+        // Who the request is from, as far as rate limiting and the access
+        // log are concerned.
+        let mut client_addr = remote_addr;
+        let (request, response, rejected) = match read_request(&mut reader, &limits) {
+            Ok(mut request) => {
+                let started = Instant::now();
+                if let Some(addr) = remote_addr {
+                    request.remote_addr = addr;
+                    client_addr = Some(forwarded_client(addr, &request, &state.config));
+                }
+                state.metrics.record_request();
+                // Handlers, and upstreams behind the proxy, see the same ID
+                // as the client and the access log.
+                if !request
+                    .header("X-Request-Id")
+                    .is_some_and(request_id::is_valid)
+                {
+                    request
+                        .headers
+                        .retain(|(name, _)| !name.eq_ignore_ascii_case("X-Request-Id"));
+                    request
+                        .headers
+                        .push(("X-Request-Id".to_string(), request_id::generate()));
+                }
+                let limited = state
+                    .rate_limiter
+                    .as_ref()
+                    .zip(client_addr)
+                    .and_then(|(limiter, addr)| limiter.check(addr.ip()).err());
+                let response = if let Some(retry_after) = limited {
+                    let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+                    Response::error(StatusCode::TOO_MANY_REQUESTS)
+                        .header("Retry-After", secs.to_string())
+                } else if unmet_expectation(&request) {
+                    Response::error(StatusCode::EXPECTATION_FAILED)
+                } else if !is_supported_version(&request.version) {
+                    Response::error(StatusCode::HTTP_VERSION_NOT_SUPPORTED)
+                } else if request.method == Method::Options && request.target == "*" {
+                    Response::new(StatusCode::OK).header("Allow", server_methods(&state.config))
+                } else if request.method == Method::Trace {
+                    trace(&request, &state.config)
+                } else {
+                    respond_within(&request, state)
+                };
+                let response = if state.config.response_timing {
+                    let millis = started.elapsed().as_secs_f64() * 1000.0;
+                    response.header("X-Response-Time", format!("{millis:.3}ms"))
+                } else {
+                    response
+                };
+                (Some(request), response, None)
+            }
+            Err(ParseError::ConnectionClosed) => {
+                if served == 0 {
+                    log::debug!("Connection closed before sending a request");
+                }
+                return Ok(());
+            }
+            Err(
+                err @ (ParseError::InvalidRequestLine(_)
+                | ParseError::InvalidHeader(_)
+                | ParseError::InvalidQuery(_)
+                | ParseError::InvalidChunk(_)),
+            ) => (None, Response::new(StatusCode::BAD_REQUEST), Some(err)),
+            Err(
+                err @ (ParseError::LineTooLong
+                | ParseError::HeadersTooLarge
+                | ParseError::TooManyHeaders),
+            ) => (
+                None,
+                Response::new(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+                Some(err),
+            ),
+            Err(err @ ParseError::BodyTooLarge) => (
+                None,
+                Response::new(StatusCode::PAYLOAD_TOO_LARGE),
+                Some(err),
+            ),
+            Err(err) => return Err(err.into()),
+        };
+
+        let response = with_error_page(response, &state.config);
+        let request_id = request
+            .as_ref()
+            .and_then(|request| request.header("X-Request-Id"))
+            .map_or_else(request_id::generate, str::to_string);
+        let response = if response.header_value("X-Request-Id").is_some() {
+            response
+        } else {
+            response.header("X-Request-Id", &request_id)
+        };
+        let server_name = &state.config.server_name;
+        let mut response = if server_name.is_empty() || response.header_value("Server").is_some() {
+            response
+        } else {
+            response.header("Server", server_name)
+        };
+        // HTTP/1.0 has no chunked encoding, so a body of unknown length is
+        // sent as it is and ended by closing the connection.
+        let unframed = request
+            .as_ref()
+            .is_some_and(|request| request.version == "HTTP/1.0")
+            && response.unchunk();
+        if unframed {
+            response.remove_header("Connection");
+        }
+
+        let limit_reached = state
+            .config
+            .keep_alive_max_requests
+            .is_some_and(|max| served + 1 >= max);
+        let keep_alive = keep_alive(request.as_ref(), &response)
+            && !unframed
+            && !limit_reached
+            && !state.draining.load(Ordering::SeqCst);
+        let mut response = if response.header_value("Connection").is_some() {
+            response
+        } else {
+            response.header(
+                "Connection",
+                if keep_alive { "keep-alive" } else { "close" },
+            )
+        };
+
+        let head_only = request
+            .as_ref()
+            .is_some_and(|request| request.method == Method::Head);
+        let written = if head_only {
+            response.write_head_to(reader.get_mut()).map(|()| 0)
+        } else {
+            response.write_to(reader.get_mut())
+        };
+        state.metrics.record_response(response.status);
+        if let Some(access_log) = &state.access_log {
+            let body_bytes = written.as_ref().map_or(0, |&bytes| bytes as usize);
+            access_log.record(
+                client_addr,
+                request.as_ref(),
+                &request_id,
+                response.status,
+                body_bytes,
+            );
+        }
+        if let Err(err) = written {
+            if is_disconnect(&err) {
+                log::debug!("Client went away mid-response: {err}");
+                return Ok(());
+            }
+            return Err(err.into());
+        }
+        if let Some(err) = rejected {
+            return Err(ConnectionError::Parse(err));
+        }
+        if let Some(on_upgrade) = response.take_upgrade() {
+            let buffered = reader.buffer().to_vec();
+            let stream = reader.into_inner();
+            // The new protocol decides for itself how long a connection may
+            // sit idle.
+            stream.set_read_timeout(None)?;
+            on_upgrade(Upgraded::new(buffered, Box::new(stream), remote_addr));
+            return Ok(());
+        }
+        served += 1;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// What `OPTIONS *` advertises: every method the server parses as more
+/// than an extension method, `TRACE` only when it's allowed. Individual
+/// paths may allow fewer.
+fn server_methods(config: &ServerConfig) -> &'static str {
+    if config.allow_trace {
+        "GET, HEAD, POST, PUT, DELETE, OPTIONS, TRACE"
+    } else {
+        "GET, HEAD, POST, PUT, DELETE, OPTIONS"
+    }
+}
+
+/// Headers left out of a `TRACE` echo because they carry credentials.
+const TRACE_HIDDEN_HEADERS: [&str; 3] = ["Authorization", "Proxy-Authorization", "Cookie"];
+
+/// Echoes the request line and headers back as `message/http`, or answers
+/// `405` when `TRACE` isn't allowed.
+fn trace(request: &Request, config: &ServerConfig) -> Response {
+    if !config.allow_trace {
+        return Response::error(StatusCode::METHOD_NOT_ALLOWED)
+            .header("Allow", server_methods(config));
+    }
+    let mut body = format!(
+        "{} {} {}\r\n",
+        request.method, request.target, request.version
+    );
+    for (name, value) in &request.headers {
+        if !TRACE_HIDDEN_HEADERS
+            .iter()
+            .any(|hidden| name.eq_ignore_ascii_case(hidden))
+        {
+            body.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    body.push_str("\r\n");
+    Response::new(StatusCode::OK)
+        .header("Content-Type", "message/http")
+        .body(body)
+}
+
+/// The client a trusted proxy forwarded the request for, or `peer` itself.
+///
+/// `X-Forwarded-For` is read from the right, the end the nearest proxy
+/// appended to, skipping the trusted proxies. The first address that isn't
+/// one is the client: anything further left is whatever that client chose
+/// to send, and could be forged. The port of a forwarded client is unknown
+/// and reported as `0`.
+fn forwarded_client(peer: SocketAddr, request: &Request, config: &ServerConfig) -> SocketAddr {
+    let trusted = |ip: &IpAddr| config.trusted_proxies.contains(ip);
+    if !trusted(&peer.ip()) {
+        return peer;
+    }
+    let hops = request
+        .header_all("X-Forwarded-For")
+        .flat_map(|value| value.split(','))
+        .map(str::trim);
+    let mut client = peer;
+    for hop in hops.rev() {
+        let Ok(ip) = hop.parse::<IpAddr>() else {
+            break;
+        };
+        client = SocketAddr::new(ip, 0);
+        if !trusted(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// Like `respond`, but gives up with a `503` once the configured request
+/// timeout elapses.
+fn respond_within(request: &Request, state: &Arc<ServerState>) -> Response {
+    let Some(timeout) = state.config.request_timeout else {
+        return respond(request, state);
+    };
+    let (done, completion) = mpsc::channel();
+    let handler = {
+        let request = request.clone();
+        let state = state.clone();
+        thread::Builder::new()
+            .name("handler".to_string())
+            .spawn(move || {
+                let _ = done.send(respond(&request, &state));
+            })
+    };
+    if let Err(err) = handler {
+        log::warn!("Failed to spawn handler thread, running inline: {err}");
+        return respond(request, state);
+    }
+    completion.recv_timeout(timeout).unwrap_or_else(|_| {
+        log::warn!(
+            "Handler for {} {} timed out after {timeout:?}",
+            request.method,
+            request.target
+        );
+        Response::error(StatusCode::SERVICE_UNAVAILABLE).header("Connection", "close")
+    })
+}
+
+/// Runs the router for `request`, turning a panicking handler into a `500`
+/// that also closes the connection, since the handler may have left shared
+/// state half-updated.
+fn respond(request: &Request, state: &ServerState) -> Response {
+    let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut response = state.router.dispatch(request);
+        if state.config.compress && request.method != Method::Head {
+            gzip_response(request, &mut response, state.config.compress_min_bytes);
+        }
+        response
+    }));
+    handled.unwrap_or_else(|payload| {
+        log::error!(
+            "Handler for {} {} panicked: {}",
+            request.method,
+            request.target,
+            panic_message(&*payload)
+        );
+        Response::error(StatusCode::INTERNAL_SERVER_ERROR).header("Connection", "close")
+    })
+}
+
+/// Swaps in the configured error page for `response`'s status, if there is
+/// one and the response has no body of its own.
+fn with_error_page(mut response: Response, config: &ServerConfig) -> Response {
+    let Some(page) = config.error_pages.get(&response.status.as_u16()) else {
+        return response;
+    };
+    let generated = !response.is_streamed()
+        && (response.body.is_empty() || response.body == Response::error(response.status).body);
+    if !generated {
+        return response;
+    }
+    match fs::read(page) {
+        Ok(body) => {
+            response.remove_header("Content-Type");
+            response
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(body)
+        }
+        Err(err) => {
+            log::warn!("Failed to read error page {}: {err}", page.display());
+            response
+        }
+    }
+}
+
+/// Parses the next request. A client that sent `Expect: 100-continue` is
+/// told to go ahead with its body once the headers check out; one whose body
+/// is too large gets the `413` instead, without sending it first.
+fn read_request(
+    reader: &mut BufReader<impl Connection>,
+    limits: &ParseLimits,
+) -> Result<Request, ParseError> {
+    let mut request = Request::parse_head_with_limits(reader, limits)?;
+    let expects_continue =
+        request.version == "HTTP/1.1" && has_token(request.header("Expect"), "100-continue");
+    if expects_continue {
+        let len = request.content_length()?.unwrap_or(0);
+        let chunked = request.is_chunked()?;
+        if chunked || (len > 0 && len <= limits.max_body_bytes as u64) {
+            let writer = reader.get_mut();
+            writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            writer.flush()?;
+        }
+    }
+    request.read_body(reader, limits)?;
+    Ok(request)
+}
+
+/// Whether the request carries an `Expect` the server can't meet; only
+/// `100-continue` is understood.
+fn unmet_expectation(request: &Request) -> bool {
+    request
+        .header("Expect")
+        .is_some_and(|expect| !expect.eq_ignore_ascii_case("100-continue"))
+}
+
+/// Waits up to the keep-alive timeout for the next request to start arriving
+/// on an idle connection, then restores the per-read timeout.
+///
+/// Returns `false` when the client closed the connection or let it sit idle
+/// for too long.
+fn wait_for_request(
+    reader: &mut BufReader<impl Connection>,
+    config: &ServerConfig,
+) -> io::Result<bool> {
+    reader
+        .get_ref()
+        .set_read_timeout(Some(config.keep_alive_timeout))?;
+    match reader.fill_buf() {
+        Ok([]) => return Ok(false),
+        Ok(_) => {}
+        Err(err) if is_timeout(&err) => {
+            log::debug!("Closing idle keep-alive connection");
+            return Ok(false);
+        }
+        Err(err) => return Err(err),
+    }
+    reader.get_ref().set_read_timeout(config.read_timeout)?;
+    Ok(true)
+}
+
+/// Decides whether the connection stays open after `response`.
+///
+/// HTTP/1.1 connections persist unless the client sends `Connection:
+/// close`, HTTP/1.0 ones only when it asks for `keep-alive`. Unparseable
+/// requests and handlers that set `Connection: close` end the connection.
+fn keep_alive(request: Option<&Request>, response: &Response) -> bool {
+    let Some(request) = request else {
+        return false;
+    };
+    if has_token(response.header_value("Connection"), "close") {
+        return false;
+    }
+    let connection = request.header("Connection");
+    match request.version.as_str() {
+        "HTTP/1.1" => !has_token(connection, "close"),
+        "HTTP/1.0" => has_token(connection, "keep-alive"),
+        _ => false,
+    }
+}
+
+/// HTTP/1.0 is served with the same routes as HTTP/1.1; only the default
+/// connection handling differs.
+fn is_supported_version(version: &str) -> bool {
+    matches!(version, "HTTP/1.0" | "HTTP/1.1")
+}
+
+/// Checks a comma-separated header value such as `keep-alive, Upgrade` for `token`.
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|value| {
+        value
+            .split(',')
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+/// The client closing its end early, as browsers do when a navigation is
+/// cancelled. Not worth more than a debug line.
+fn is_disconnect(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionReset | ErrorKind::BrokenPipe | ErrorKind::ConnectionAborted
+    )
+}
+
+/// Socket timeouts surface as `WouldBlock` on Unix and `TimedOut` on Windows.
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use crate::{
+        listener::MemoryConnection,
+        test_support::{content_length, split_head, TempDir},
+    };
+
+    /// A configuration serving `root` that keeps test output quiet.
+    fn test_config(root: &Path) -> ServerConfig {
+        ServerConfig {
+            document_root: root.to_path_buf(),
+            access_log: AccessLog::Disabled,
+            log_level: Some(LogLevel::Error),
+            ..ServerConfig::default()
+        }
+    }
+
+    /// Like `test_config`, listening on an ephemeral loopback port.
+    fn local_config(root: &Path) -> ServerConfig {
+        ServerConfig {
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            ..test_config(root)
+        }
+    }
+
+    /// Sends `request` to `addr` on a new connection and reads the response
+    /// until the server closes it.
+    fn send(addr: SocketAddr, request: &str) -> String {
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn test_state(config: ServerConfig, router: Router) -> Arc<ServerState> {
+        let document_root = Arc::new(RwLock::new(Arc::new(config.document_root.clone())));
+        Arc::new(ServerState::new(config, router, document_root, Arc::new(Metrics::new())).unwrap())
+    }
+
+    /// Feeds `input` to `handle_connection`, as if a client had sent it and
+    /// then stopped sending, and returns everything written back.
+    fn exchange(state: &Arc<ServerState>, input: &[u8]) -> Result<Vec<u8>, ConnectionError> {
+        let (connection, output) = MemoryConnection::new(input);
+        handle_connection(connection, None, state)?;
+        let written = output.lock().unwrap().clone();
+        Ok(written)
+    }
+
+    /// Like `exchange`, for input the connection refuses to go on with.
+    /// Returns what was written anyway, as text, and the error.
+    fn exchange_rejected(state: &Arc<ServerState>, input: &[u8]) -> (String, ConnectionError) {
+        let (connection, output) = MemoryConnection::new(input);
+        let err = handle_connection(connection, None, state).expect_err("input was accepted");
+        let written = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        (written, err)
+    }
+
+    #[test]
+    fn binds_an_ephemeral_port() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let server = Server::bind(local_config(root.path())).unwrap();
+        let addr = server.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+
+        let handle = server.run();
+        let response = send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        handle.shutdown();
+    }
+
+    /// Runs the server in a copy of this test binary that only runs this
+    /// test, so the signal reaches nothing else.
+    #[cfg(unix)]
+    #[test]
+    fn sigterm_shuts_the_server_down() {
+        use std::process::{Command, Stdio};
+
+        const CHILD: &str = "WEB_SERVER_SIGTERM_TEST_ROOT";
+        if let Some(root) = std::env::var_os(CHILD) {
+            let config = ServerConfig {
+                log_level: Some(LogLevel::Info),
+                ..local_config(Path::new(&root))
+            };
+            run_server(config).unwrap();
+            return;
+        }
+
+        let root = TempDir::new();
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "server::tests::sigterm_shuts_the_server_down"])
+            .env(CHILD, root.path())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stderr = BufReader::new(child.stderr.take().unwrap());
+        let mut line = String::new();
+        while !line.contains("Server started on") {
+            line.clear();
+            assert_ne!(
+                stderr.read_line(&mut line).unwrap(),
+                0,
+                "child exited early"
+            );
+        }
+
+        let pid = child.id().to_string();
+        let killed = Command::new("kill").args(["-TERM", &pid]).status().unwrap();
+        assert!(killed.success());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let status = loop {
+            if let Some(status) = child.try_wait().unwrap() {
+                break status;
+            }
+            if Instant::now() > deadline {
+                let _ = child.kill();
+                panic!("server didn't exit after SIGTERM");
+            }
+            thread::sleep(Duration::from_millis(20));
+        };
+        assert!(status.success(), "{status}");
+    }
+
+    #[test]
+    fn each_server_reports_the_port_it_was_given() {
+        let (first, second) = (TempDir::new(), TempDir::new());
+        first.write("index.html", "first");
+        second.write("index.html", "second");
+        let first = Server::bind(local_config(first.path())).unwrap();
+        let second = Server::bind(local_config(second.path())).unwrap();
+        let addrs = (first.local_addr().unwrap(), second.local_addr().unwrap());
+        assert_ne!(addrs.0.port(), addrs.1.port());
+
+        let (first, second) = (first.run(), second.run());
+        assert_eq!(first.local_addrs(), [addrs.0]);
+        let request = "GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        assert!(send(addrs.0, request).ends_with("\r\n\r\nfirst"));
+        assert!(send(addrs.1, request).ends_with("\r\n\r\nsecond"));
+        first.shutdown();
+        second.shutdown();
+    }
+
+    #[test]
+    fn shutdown_stops_the_server() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let handle = Server::bind(local_config(root.path())).unwrap().run();
+        let addr = handle.local_addrs()[0];
+        let response = send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+
+        assert_eq!(handle.shutdown(), 0);
+        assert!(std::net::TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn accepted_streams_get_tcp_nodelay_as_configured() {
+        let root = TempDir::new();
+        let options = ListenOptions {
+            backlog: 16,
+            reuse_address: true,
+        };
+        let listener = listener::bind_tcp(SocketAddr::from(([127, 0, 0, 1], 0)), &options).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        for tcp_nodelay in [true, false] {
+            let _client = std::net::TcpStream::connect(addr).unwrap();
+            let stream = listener.accept().unwrap();
+            let config = ServerConfig {
+                tcp_nodelay,
+                ..test_config(root.path())
+            };
+            configure_stream(&stream, &config).unwrap();
+            let Stream::Tcp(stream) = stream else {
+                panic!("accepted a non-TCP stream");
+            };
+            assert_eq!(stream.nodelay().unwrap(), tcp_nodelay);
+            assert_eq!(stream.read_timeout().unwrap(), config.read_timeout);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serves_over_a_unix_socket() {
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let socket = root.path().join("server.sock");
+        // Left behind as by a server that didn't get to clean up.
+        drop(UnixListener::bind(&socket).unwrap());
+        let config = ServerConfig {
+            unix_socket: Some(socket.clone()),
+            ..test_config(root.path())
+        };
+        let server = Server::bind(config).unwrap();
+        assert!(server.local_addrs().is_empty());
+        let handle = server.run();
+
+        let mut client = UnixStream::connect(&socket).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("<h1>hi</h1>"), "{response}");
+        handle.shutdown();
+    }
+
+    #[test]
+    fn handle_connection_serves_an_in_memory_stream() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+
+        let output = exchange(&state, b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{output}");
+        assert!(output.ends_with("\r\n\r\n<h1>hi</h1>"), "{output}");
+    }
+
+    #[test]
+    fn bodies_are_exactly_content_length_bytes() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+
+        for target in ["/", "/missing"] {
+            let request = format!("GET {target} HTTP/1.1\r\nConnection: close\r\n\r\n");
+            let output = exchange(&state, request.as_bytes()).unwrap();
+            let (head, body) = split_head(&output);
+            assert_eq!(body.len(), content_length(&head), "{target}: {head}");
+        }
+    }
+
+    #[test]
+    fn closed_connection_gets_no_response() {
+        let root = TempDir::new();
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+        assert_eq!(exchange(&state, b"").unwrap(), b"");
+    }
+
+    #[test]
+    fn malformed_request_line_is_a_bad_request() {
+        let root = TempDir::new();
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+        for input in [
+            &b"GET\r\n\r\n"[..],
+            b"\r\n\r\n",
+            b"GET /\xff HTTP/1.1\r\n\r\n",
+        ] {
+            let (output, err) = exchange_rejected(&state, input);
+            assert!(
+                output.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+                "{output}"
+            );
+            assert!(matches!(err, ConnectionError::Parse(_)), "{err}");
+        }
+    }
+
+    #[test]
+    fn head_requests_get_headers_only() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+
+        let output = exchange(&state, b"HEAD / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let (head, body) = split_head(&output);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert_eq!(content_length(&head), 11);
+        assert!(body.is_empty(), "{body:?}");
+    }
+
+    #[test]
+    fn keeps_the_connection_alive_between_requests() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let handle = Server::bind(local_config(root.path())).unwrap().run();
+
+        let response = send(
+            handle.local_addrs()[0],
+            "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(
+            response.matches("HTTP/1.1 200 OK\r\n").count(),
+            2,
+            "{response}"
+        );
+        assert_eq!(response.matches("Connection: keep-alive\r\n").count(), 1);
+        assert!(response.ends_with("<h1>hi</h1>"), "{response}");
+        handle.shutdown();
+    }
+
+    #[test]
+    fn silent_clients_are_dropped_after_the_read_timeout() {
+        let root = TempDir::new();
+        let config = ServerConfig {
+            read_timeout: Some(Duration::from_millis(100)),
+            ..local_config(root.path())
+        };
+        let handle = Server::bind(config).unwrap().run();
+
+        let mut client = std::net::TcpStream::connect(handle.local_addrs()[0]).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let started = Instant::now();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty(), "{response:?}");
+        assert!(started.elapsed() < Duration::from_secs(2));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn sleep_delay_comes_from_the_query() {
+        let sleep = |target: &str| {
+            let raw = format!("GET {target} HTTP/1.1\r\n\r\n");
+            sleep_duration(&Request::parse(&mut raw.as_bytes()).unwrap())
+        };
+        assert_eq!(sleep("/sleep?ms=100"), Duration::from_millis(100));
+        assert_eq!(sleep("/sleep?ms=999999"), MAX_SLEEP);
+        assert_eq!(sleep("/sleep?ms=soon"), DEFAULT_SLEEP);
+        assert_eq!(sleep("/sleep"), DEFAULT_SLEEP);
+
+        let root = TempDir::new();
+        root.write("sleep.html", "zzz");
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+        let started = Instant::now();
+        let output = exchange(
+            &state,
+            b"GET /sleep?ms=100 HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let elapsed = started.elapsed();
+        assert!(output.unwrap().starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(elapsed >= Duration::from_millis(100), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+    }
+
+    #[test]
+    fn connection_header_says_whether_the_connection_stays_open() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+        let responses = |input: &[u8]| {
+            let output = String::from_utf8(exchange(&state, input).unwrap()).unwrap();
+            let count = output.matches("HTTP/1.1 200 OK\r\n").count();
+            (output, count)
+        };
+
+        // HTTP/1.0 closes by default, so the second request goes unread.
+        let (output, count) = responses(b"GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n");
+        assert_eq!(count, 1, "{output}");
+        assert!(output.contains("Connection: close\r\n"), "{output}");
+
+        let (output, count) =
+            responses(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        assert_eq!(count, 1, "{output}");
+        assert!(output.contains("Connection: close\r\n"), "{output}");
+
+        let (output, count) = responses(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+        assert_eq!(count, 1, "{output}");
+        assert!(output.contains("Connection: keep-alive\r\n"), "{output}");
+    }
+
+    #[test]
+    fn metrics_count_requests_and_responses() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let handle = Server::bind(local_config(root.path())).unwrap().run();
+        let addr = handle.local_addrs()[0];
+
+        send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        send(addr, "GET /missing HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let response = send(addr, "GET /metrics HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        // The `/metrics` request counts itself but its response isn't sent yet.
+        assert!(response.contains("\nhttp_requests_total 3\n"), "{response}");
+        assert!(response.contains("\nhttp_responses_total{class=\"2xx\"} 1\n"));
+        assert!(response.contains("\nhttp_responses_total{class=\"4xx\"} 1\n"));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn healthz_does_not_need_the_document_root() {
+        let root = TempDir::new();
+        let config = test_config(&root.path().join("missing"));
+        let state = test_state(config.clone(), default_router(&config));
+
+        let output = exchange(
+            &state,
+            b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let output = output.unwrap();
+        let (head, body) = split_head(&output);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert_eq!(body, b"ok");
+    }
+
+    #[test]
+    fn idle_kept_alive_connections_are_closed() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let config = ServerConfig {
+            keep_alive_timeout: Duration::from_millis(200),
+            ..local_config(root.path())
+        };
+        let handle = Server::bind(config).unwrap().run();
+
+        let mut client = std::net::TcpStream::connect(handle.local_addrs()[0]).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let started = Instant::now();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        let elapsed = started.elapsed();
+        assert!(
+            response.contains("Connection: keep-alive\r\n"),
+            "{response}"
+        );
+        assert!(response.ends_with("<h1>hi</h1>"), "{response}");
+        assert!(elapsed >= Duration::from_millis(150), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(3), "{elapsed:?}");
+        handle.shutdown();
+    }
+
+    #[test]
+    fn oversized_request_line_gets_431() {
+        let root = TempDir::new();
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+        let input = format!(
+            "GET /{} HTTP/1.1\r\n\r\n",
+            "a".repeat(config.max_line_bytes)
+        );
+
+        let (output, err) = exchange_rejected(&state, input.as_bytes());
+        assert!(
+            output.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+            "{output}"
+        );
+        assert!(
+            matches!(err, ConnectionError::Parse(ParseError::LineTooLong)),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn oversized_body_gets_413() {
+        let root = TempDir::new();
+        let config = ServerConfig {
+            max_body_bytes: 4,
+            ..test_config(root.path())
+        };
+        let state = test_state(config.clone(), default_router(&config));
+
+        let (output, _) = exchange_rejected(
+            &state,
+            b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+        );
+        assert!(
+            output.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn echo_route_returns_the_request_body() {
+        let root = TempDir::new();
+        let handle = Server::bind(local_config(root.path())).unwrap().run();
+
+        let response = send(
+            handle.local_addrs()[0],
+            "POST /echo HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\
+             Connection: close\r\n\r\nhello",
+        );
+        let (head, body) = split_head(response.as_bytes());
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains("Content-Type: text/plain\r\n"), "{head}");
+        assert_eq!(body, b"hello");
+        handle.shutdown();
+    }
+
+    #[test]
+    fn panicking_handler_gets_a_500() {
+        let root = TempDir::new();
+        let mut router = Router::new();
+        router
+            .route(Method::Get, "/boom", |_| panic!("handler failed"))
+            .route(Method::Get, "/fine", |_| Response::new(StatusCode::OK));
+        let state = test_state(test_config(root.path()), router);
+
+        let output = exchange(
+            &state,
+            b"GET /boom HTTP/1.1\r\n\r\nGET /fine HTTP/1.1\r\n\r\n",
+        );
+        let output = String::from_utf8(output.unwrap()).unwrap();
+        assert!(
+            output.starts_with("HTTP/1.1 500 Internal Server Error\r\n"),
+            "{output}"
+        );
+        // The handler may have left shared state broken, so the connection
+        // doesn't carry on to the next request.
+        assert!(output.contains("Connection: close\r\n"), "{output}");
+        assert!(!output.contains("HTTP/1.1 200 OK\r\n"), "{output}");
+
+        let output = exchange(&state, b"GET /fine HTTP/1.1\r\n\r\n").unwrap();
+        assert!(output.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn upgraded_connection_is_handed_to_the_callback() {
+        let root = TempDir::new();
+        let mut router = Router::new();
+        router.route(Method::Get, "/ws", |request| {
+            crate::accept_websocket(request, |mut upgraded| {
+                let mut frame = [0; 4];
+                upgraded.read_exact(&mut frame).unwrap();
+                assert_eq!(&frame, b"ping");
+                upgraded.write_all(b"pong").unwrap();
+            })
+        });
+        let state = test_state(test_config(root.path()), router);
+
+        // The client's first bytes arrive along with the handshake, so they
+        // reach the callback from the request buffer.
+        let output = exchange(
+            &state,
+            b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n\
+              ping",
+        )
+        .unwrap();
+        let (head, rest) = split_head(&output);
+        assert!(
+            head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+            "{head}"
+        );
+        assert!(
+            head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"),
+            "{head}"
+        );
+        assert_eq!(rest, b"pong");
+    }
+
+    #[test]
+    fn slow_handler_times_out_with_503() {
+        let root = TempDir::new();
+        let mut router = Router::new();
+        router.route(Method::Get, "/slow", |_| {
+            thread::sleep(Duration::from_millis(500));
+            Response::new(StatusCode::OK)
+        });
+        let config = ServerConfig {
+            request_timeout: Some(Duration::from_millis(50)),
+            ..test_config(root.path())
+        };
+        let state = test_state(config, router);
+
+        let started = Instant::now();
+        let output = exchange(&state, b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{output}"
+        );
+        assert!(output.contains("Connection: close\r\n"), "{output}");
+        assert!(started.elapsed() < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn malformed_request_is_a_parse_error() {
+        let root = TempDir::new();
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+        let (_, err) = exchange_rejected(&state, b"GET / HTTP/1.1\r\nno colon here\r\n\r\n");
+        assert!(
+            matches!(err, ConnectionError::Parse(ParseError::InvalidHeader(_))),
+            "{err}"
+        );
+        assert!(err.to_string().starts_with("bad request: "), "{err}");
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn timeouts_and_read_errors_convert_to_connection_errors() {
+        let timed_out = io::Error::new(ErrorKind::WouldBlock, "read timed out");
+        assert!(matches!(
+            ConnectionError::from(timed_out),
+            ConnectionError::Timeout
+        ));
+        let reset = ParseError::Io(io::Error::new(ErrorKind::ConnectionReset, "reset"));
+        assert!(matches!(
+            ConnectionError::from(reset),
+            ConnectionError::Io(err) if err.kind() == ErrorKind::ConnectionReset
+        ));
+    }
+
+    #[test]
+    fn http_1_0_is_served_and_unknown_versions_are_not() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+
+        let output = exchange(&state, b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{output}");
+        assert!(output.contains("Connection: close\r\n"), "{output}");
+
+        let output = exchange(&state, b"GET / HTTP/2.0\r\n\r\n").unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn chunked_bodies_reach_http_1_0_clients_unframed() {
+        let root = TempDir::new();
+        let mut router = Router::new();
+        router.route(Method::Get, "/events", |_| {
+            Response::new(StatusCode::OK).chunked(io::Cursor::new(b"data: one\n\n".to_vec()))
+        });
+        let state = test_state(test_config(root.path()), router);
+
+        let output = exchange(
+            &state,
+            b"GET /events HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+        );
+        let output = String::from_utf8(output.unwrap()).unwrap();
+        assert!(!output.contains("Transfer-Encoding"), "{output}");
+        assert!(output.contains("Connection: close\r\n"), "{output}");
+        assert!(output.ends_with("\r\n\r\ndata: one\n\n"), "{output}");
+
+        let output = exchange(&state, b"GET /events HTTP/1.1\r\n\r\n").unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.contains("Transfer-Encoding: chunked\r\n"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn clients_over_their_rate_get_429() {
+        let root = TempDir::new();
+        let config = ServerConfig {
+            rate_limit: Some(RateLimit {
+                requests_per_second: 1.0,
+                burst: 2,
+            }),
+            ..local_config(root.path())
+        };
+        let handle = Server::bind(config).unwrap().run();
+
+        let responses: Vec<String> = (0..5)
+            .map(|_| {
+                send(
+                    handle.local_addrs()[0],
+                    "GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n",
+                )
+            })
+            .collect();
+        let limited: Vec<_> = responses
+            .iter()
+            .filter(|response| response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"))
+            .collect();
+        assert!(limited.len() >= 2, "{responses:?}");
+        assert!(limited[0].contains("Retry-After: 1\r\n"), "{}", limited[0]);
+        assert!(responses[0].starts_with("HTTP/1.1 200 OK\r\n"));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn bind_rejects_a_rate_limit_that_never_refills() {
+        let root = TempDir::new();
+        let config = ServerConfig {
+            rate_limit: Some(RateLimit {
+                requests_per_second: 0.0,
+                burst: 5,
+            }),
+            ..local_config(root.path())
+        };
+        let err = Server::bind(config).err().expect("bind should fail");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("requests_per_second"), "{err}");
+    }
+
+    #[test]
+    fn shrinking_the_pool_does_not_hold_up_shutdown() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let config = ServerConfig {
+            pool_size: 2,
+            keep_alive_timeout: Duration::from_secs(30),
+            shutdown_grace_period: Duration::from_secs(1),
+            ..local_config(root.path())
+        };
+        let handle = Server::bind(config.clone()).unwrap().run();
+        let addr = handle.local_addrs()[0];
+
+        // Both workers end up waiting on a kept-alive connection.
+        let clients: Vec<_> = (0..2)
+            .map(|_| {
+                let mut client = std::net::TcpStream::connect(addr).unwrap();
+                client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+                let mut response = Vec::new();
+                let mut buf = [0; 1024];
+                while !response.ends_with(b"<h1>hi</h1>") {
+                    let n = client.read(&mut buf).unwrap();
+                    assert!(n > 0, "connection closed early");
+                    response.extend_from_slice(&buf[..n]);
+                }
+                client
+            })
+            .collect();
+
+        handle
+            .reload(ServerConfig {
+                pool_size: 1,
+                ..config
+            })
+            .unwrap();
+        let started = Instant::now();
+        handle.shutdown();
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "shutdown took {:?}",
+            started.elapsed()
+        );
+        drop(clients);
+    }
+
+    #[test]
+    fn reload_swaps_the_document_root_for_later_requests() {
+        let old_root = TempDir::new();
+        old_root.write("index.html", "old");
+        let new_root = TempDir::new();
+        new_root.write("index.html", "new");
+        let config = local_config(old_root.path());
+
+        // The default file routes, plus one that picks up the root and then
+        // waits, as a slow file request would.
+        let document_root = Arc::new(RwLock::new(Arc::new(config.document_root.clone())));
+        let mut router = router_with_root(&config, document_root.clone());
+        let (entered_tx, entered) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        let entered_tx = std::sync::Mutex::new(entered_tx);
+        let release_rx = std::sync::Mutex::new(release_rx);
+        {
+            let document_root = document_root.clone();
+            router.route(Method::Get, "/hold", move |request| {
+                let root = current_root(&document_root);
+                entered_tx.lock().unwrap().send(()).unwrap();
+                let _ = release_rx.lock().unwrap().recv();
+                serve_path(&root, "/index.html", request)
+            });
+        }
+        let handle = Server::bind_inner(config.clone(), router, document_root)
+            .unwrap()
+            .run();
+        let addr = handle.local_addrs()[0];
+
+        let request = "GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let held =
+            thread::spawn(move || send(addr, "GET /hold HTTP/1.1\r\nConnection: close\r\n\r\n"));
+        entered.recv().unwrap();
+
+        handle
+            .reload(ServerConfig {
+                document_root: new_root.path().to_path_buf(),
+                ..config.clone()
+            })
+            .unwrap();
+        assert!(send(addr, request).ends_with("\r\n\r\nnew"));
+
+        release.send(()).unwrap();
+        let held = held.join().unwrap();
+        assert!(held.starts_with("HTTP/1.1 200 OK\r\n"), "{held}");
+        assert!(held.ends_with("\r\n\r\nold"), "{held}");
+
+        let moved = ServerConfig {
+            addr: SocketAddr::from(([127, 0, 0, 1], 1)),
+            ..config
+        };
+        assert!(matches!(
+            handle.reload(moved),
+            Err(ReloadError::RestartRequired("addr"))
+        ));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn reload_rejects_a_missing_document_root() {
+        let root = TempDir::new();
+        root.write("index.html", "old");
+        let config = local_config(root.path());
+        let handle = Server::bind(config.clone()).unwrap().run();
+        let addr = handle.local_addrs()[0];
+
+        let err = handle
+            .reload(ServerConfig {
+                document_root: root.path().join("missing"),
+                pool_size: 1,
+                ..config.clone()
+            })
+            .unwrap_err();
+        assert!(matches!(err, ReloadError::DocumentRoot(_)), "{err:?}");
+        assert!(err.to_string().contains("missing"), "{err}");
+
+        let not_a_dir = ServerConfig {
+            document_root: root.path().join("index.html"),
+            ..config
+        };
+        assert!(matches!(
+            handle.reload(not_a_dir),
+            Err(ReloadError::DocumentRoot(_))
+        ));
+
+        let response = send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\nold"), "{response}");
+        handle.shutdown();
+    }
+
+    /// A router whose `/hold` requests report that they started on the
+    /// returned receiver, then wait for a message on the returned sender.
+    fn holding_router() -> (Router, mpsc::Receiver<()>, mpsc::Sender<()>) {
+        let (entered_tx, entered) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        let entered_tx = std::sync::Mutex::new(entered_tx);
+        let release_rx = std::sync::Mutex::new(release_rx);
+        let mut router = Router::new();
+        router
+            .route(Method::Get, "/hold", move |_| {
+                entered_tx.lock().unwrap().send(()).unwrap();
+                let _ = release_rx.lock().unwrap().recv();
+                Response::new(StatusCode::OK)
+            })
+            .route(Method::Get, "/fast", |_| Response::new(StatusCode::OK));
+        (router, entered, release)
+    }
+
+    #[test]
+    fn connections_over_the_limit_wait_to_be_accepted() {
+        let root = TempDir::new();
+        let (router, entered, release) = holding_router();
+        let config = ServerConfig {
+            pool_size: 4,
+            max_connections: Some(2),
+            ..local_config(root.path())
+        };
+        let handle = Server::bind_with_router(config, router).unwrap().run();
+        let addr = handle.local_addrs()[0];
+
+        let request = "GET /hold HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let holders: Vec<_> = (0..2)
+            .map(|_| thread::spawn(move || send(addr, request)))
+            .collect();
+        for _ in 0..2 {
+            entered.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+
+        // The connection only reaches the listen backlog, so nothing answers
+        // it while both slots are taken, even with workers to spare.
+        let mut third = std::net::TcpStream::connect(addr).unwrap();
+        third
+            .write_all(b"GET /fast HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        third
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let mut byte = [0];
+        let err = third.read(&mut byte).unwrap_err();
+        assert!(
+            matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+            "{err}"
+        );
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        for holder in holders {
+            assert!(holder.join().unwrap().starts_with("HTTP/1.1 200 OK\r\n"));
+        }
+        third
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut response = String::new();
+        third.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        handle.shutdown();
+    }
+
+    #[test]
+    fn expect_continue_is_answered_before_the_body_is_sent() {
+        let root = TempDir::new();
+        let handle = Server::bind(local_config(root.path())).unwrap().run();
+
+        let mut client = std::net::TcpStream::connect(handle.local_addrs()[0]).unwrap();
+        client
+            .write_all(
+                b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\
+                  Connection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut interim = [0; 25];
+        client.read_exact(&mut interim).unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        client.write_all(b"hello").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nhello"), "{response}");
+        handle.shutdown();
+    }
+
+    #[test]
+    fn rejected_expect_continue_gets_only_the_final_status() {
+        let root = TempDir::new();
+        let config = ServerConfig {
+            max_body_bytes: 4,
+            ..test_config(root.path())
+        };
+        let state = test_state(config.clone(), default_router(&config));
+
+        let (output, _) = exchange_rejected(
+            &state,
+            b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+        );
+        assert!(
+            output.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+            "{output}"
+        );
+        assert!(!output.contains("100 Continue"), "{output}");
+
+        let output = exchange(
+            &state,
+            b"POST /echo HTTP/1.1\r\nContent-Length: 2\r\nExpect: 200-ok\r\n\
+              Connection: close\r\n\r\nhi",
+        )
+        .unwrap();
+        assert!(output.starts_with(b"HTTP/1.1 417 Expectation Failed\r\n"));
+    }
+
+    #[test]
+    fn responses_and_access_log_carry_the_request_id() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let log_path = root.path().join("access.log");
+        let config = ServerConfig {
+            access_log: AccessLog::File(log_path.clone()),
+            ..test_config(root.path())
+        };
+        let state = test_state(config.clone(), default_router(&config));
+        let id_of = |output: &[u8]| {
+            let (head, _) = split_head(output);
+            head.lines()
+                .find_map(|line| line.strip_prefix("X-Request-Id: "))
+                .expect("no X-Request-Id")
+                .to_string()
+        };
+
+        let output = exchange(&state, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let generated = id_of(&output);
+        assert!(request_id::is_valid(&generated), "{generated}");
+
+        let output = exchange(
+            &state,
+            b"GET / HTTP/1.1\r\nX-Request-Id: trace-42\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(id_of(&output), "trace-42");
+
+        let output = exchange(
+            &state,
+            b"GET / HTTP/1.1\r\nX-Request-Id: not ok\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+        assert_ne!(id_of(&output), "not ok");
+
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 3, "{log}");
+        assert!(lines[0].ends_with(&format!(" \"{generated}\"")), "{log}");
+        assert!(lines[1].ends_with(" \"trace-42\""), "{log}");
+    }
+
+    #[test]
+    fn in_flight_requests_finish_within_the_grace_period() {
+        let root = TempDir::new();
+        let (router, entered, release) = holding_router();
+        let config = ServerConfig {
+            shutdown_grace_period: Duration::from_secs(10),
+            ..local_config(root.path())
+        };
+        let handle = Server::bind_with_router(config, router).unwrap().run();
+        let addr = handle.local_addrs()[0];
+
+        let held =
+            thread::spawn(move || send(addr, "GET /hold HTTP/1.1\r\nConnection: close\r\n\r\n"));
+        entered.recv_timeout(Duration::from_secs(5)).unwrap();
+        let shutdown = thread::spawn(move || handle.shutdown());
+
+        // Connections arriving once draining has begun are turned away.
+        let started = Instant::now();
+        loop {
+            let response = send(addr, "GET /fast HTTP/1.1\r\nConnection: close\r\n\r\n");
+            if response.starts_with("HTTP/1.1 503 Service Unavailable\r\n") {
+                break;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "{response}");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        release.send(()).unwrap();
+        let response = held.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert_eq!(shutdown.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn connections_left_after_the_grace_period_are_counted() {
+        let root = TempDir::new();
+        let (router, entered, release) = holding_router();
+        let config = ServerConfig {
+            shutdown_grace_period: Duration::from_millis(200),
+            ..local_config(root.path())
+        };
+        let handle = Server::bind_with_router(config, router).unwrap().run();
+        let addr = handle.local_addrs()[0];
+
+        let _held =
+            thread::spawn(move || send(addr, "GET /hold HTTP/1.1\r\nConnection: close\r\n\r\n"));
+        entered.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let started = Instant::now();
+        assert_eq!(handle.shutdown(), 1);
+        let elapsed = started.elapsed();
+        assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+        drop(release);
+    }
+
+    #[test]
+    fn server_header_names_the_server_unless_configured_empty() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let request = b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+        let output = exchange(&state, request).unwrap();
+        let (head, _) = split_head(&output);
+        let expected = format!(
+            "\r\nServer: rust-toy-webserver/{}\r\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert!(head.contains(&expected), "{head}");
+
+        let config = ServerConfig {
+            server_name: String::new(),
+            ..test_config(root.path())
+        };
+        let state = test_state(config.clone(), default_router(&config));
+        let output = exchange(&state, request).unwrap();
+        let (head, _) = split_head(&output);
+        assert!(!head.contains("Server:"), "{head}");
+    }
+
+    #[test]
+    fn every_listen_address_is_served_and_shut_down() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let config = ServerConfig {
+            extra_addrs: vec![
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, 0)),
+            ],
+            ..local_config(root.path())
+        };
+        let handle = Server::bind(config).unwrap().run();
+        let addrs = handle.local_addrs().to_vec();
+        assert_eq!(addrs.len(), 3, "{addrs:?}");
+        assert!(addrs[2].is_ipv6(), "{addrs:?}");
+
+        for &addr in &addrs {
+            let response = send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+            assert!(response.ends_with("<h1>hi</h1>"), "{addr}: {response}");
+        }
+        handle.shutdown();
+        for addr in addrs {
+            assert!(std::net::TcpStream::connect(addr).is_err(), "{addr}");
+        }
+    }
+
+    #[test]
+    fn response_timing_reports_how_long_the_request_took() {
+        let root = TempDir::new();
+        root.write("sleep.html", "zzz");
+        let request = b"GET /sleep?ms=50 HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let config = ServerConfig {
+            response_timing: true,
+            ..test_config(root.path())
+        };
+        let state = test_state(config.clone(), default_router(&config));
+        let output = exchange(&state, request).unwrap();
+        let (head, _) = split_head(&output);
+        let millis: f64 = head
+            .lines()
+            .find_map(|line| line.strip_prefix("X-Response-Time: "))
+            .and_then(|value| value.strip_suffix("ms"))
+            .expect("no X-Response-Time")
+            .parse()
+            .unwrap();
+        assert!(millis >= 50.0, "{head}");
+
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+        let output = exchange(&state, request).unwrap();
+        let (head, _) = split_head(&output);
+        assert!(!head.contains("X-Response-Time"), "{head}");
+    }
+
+    /// A connection that delivers `input` and then fails every write with
+    /// `kind`, like a client that sent a request and went away.
+    struct HungUp {
+        input: io::Cursor<Vec<u8>>,
+        kind: ErrorKind,
+    }
+
+    impl Read for HungUp {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for HungUp {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(self.kind.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(self.kind.into())
+        }
+    }
+
+    impl Connection for HungUp {}
+
+    #[test]
+    fn clients_going_away_mid_response_are_not_an_error() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+        let serve = |kind| {
+            let connection = HungUp {
+                input: io::Cursor::new(b"GET / HTTP/1.1\r\n\r\n".to_vec()),
+                kind,
+            };
+            handle_connection(connection, None, &state)
+        };
+
+        for kind in [
+            ErrorKind::BrokenPipe,
+            ErrorKind::ConnectionReset,
+            ErrorKind::ConnectionAborted,
+        ] {
+            assert!(serve(kind).is_ok(), "{kind:?}");
+        }
+        assert!(matches!(
+            serve(ErrorKind::OutOfMemory),
+            Err(ConnectionError::Io(err)) if err.kind() == ErrorKind::OutOfMemory
+        ));
+    }
+
+    #[test]
+    fn configured_error_pages_replace_generated_bodies() {
+        let root = TempDir::new();
+        let page = root.write("errors/500.html", "<h1>We broke it</h1>");
+        let mut router = Router::new();
+        router
+            .route(Method::Get, "/boom", |_| panic!("handler failed"))
+            .route(Method::Get, "/own", |_| {
+                Response::new(StatusCode::INTERNAL_SERVER_ERROR).body("handler's own")
+            })
+            .route(Method::Get, "/busy", |_| {
+                Response::error(StatusCode::SERVICE_UNAVAILABLE)
+            });
+        let config = ServerConfig {
+            error_pages: HashMap::from([
+                (500, page),
+                (503, root.path().join("errors/missing.html")),
+            ]),
+            ..test_config(root.path())
+        };
+        let state = test_state(config, router);
+        let get = |path: &str| {
+            let request = format!("GET {path} HTTP/1.1\r\nConnection: close\r\n\r\n");
+            let output = exchange(&state, request.as_bytes()).unwrap();
+            let (head, body) = split_head(&output);
+            (head, String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        let (head, body) = get("/boom");
+        assert!(head.starts_with("HTTP/1.1 500 "), "{head}");
+        assert!(
+            head.contains("Content-Type: text/html; charset=utf-8\r\n"),
+            "{head}"
+        );
+        assert_eq!(content_length(&head), body.len());
+        assert_eq!(body, "<h1>We broke it</h1>");
+
+        let (_, body) = get("/own");
+        assert_eq!(body, "handler's own");
+
+        // An unreadable page falls back to the built-in one.
+        let (head, body) = get("/busy");
+        assert!(head.starts_with("HTTP/1.1 503 "), "{head}");
+        let builtin = Response::error(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body.as_bytes(), builtin.body.as_slice());
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let root = TempDir::new();
+        root.write("a.txt", "first");
+        root.write("b.txt", "third");
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+
+        // The echo body looks like a request line, so reading past it
+        // would show up as an extra response.
+        let output = exchange(
+            &state,
+            b"GET /a.txt HTTP/1.1\r\n\r\n\
+              POST /echo HTTP/1.1\r\nContent-Length: 16\r\n\r\nGET /b.txt HTTP/\
+              GET /b.txt HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+
+        let mut bodies = Vec::new();
+        let mut rest = &output[..];
+        while !rest.is_empty() {
+            let (head, after) = split_head(rest);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+            let len = content_length(&head);
+            bodies.push(String::from_utf8(after[..len].to_vec()).unwrap());
+            rest = &after[len..];
+        }
+        assert_eq!(bodies, ["first", "GET /b.txt HTTP/", "third"]);
+    }
+
+    #[test]
+    fn admin_endpoint_shuts_the_server_down_for_the_right_token() {
+        let root = TempDir::new();
+        let shutdown = "POST /admin/shutdown HTTP/1.1\r\nContent-Length: 0\r\n\
+                        Connection: close\r\n";
+
+        let handle = Server::bind(local_config(root.path())).unwrap().run();
+        let response = send(handle.local_addrs()[0], &format!("{shutdown}\r\n"));
+        assert!(response.starts_with("HTTP/1.1 404 "), "{response}");
+        handle.shutdown();
+
+        let config = ServerConfig {
+            admin_token: Some("s3cret".to_string()),
+            ..local_config(root.path())
+        };
+        let handle = Server::bind(config).unwrap().run();
+        let addr = handle.local_addrs()[0];
+        for credentials in ["", "Authorization: Bearer wrong\r\n"] {
+            let response = send(addr, &format!("{shutdown}{credentials}\r\n"));
+            assert!(
+                response.starts_with("HTTP/1.1 401 Unauthorized\r\n"),
+                "{response}"
+            );
+        }
+
+        let response = send(
+            addr,
+            &format!("{shutdown}Authorization: Bearer s3cret\r\n\r\n"),
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 202 Accepted\r\n"),
+            "{response}"
+        );
+        let (done_tx, done) = mpsc::channel();
+        thread::spawn(move || done_tx.send(handle.wait()).unwrap());
+        assert_eq!(done.recv_timeout(Duration::from_secs(5)), Ok(0));
+    }
+
+    #[test]
+    fn options_asterisk_lists_the_server_methods() {
+        let root = TempDir::new();
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+        let output = exchange(&state, b"OPTIONS * HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let (head, body) = split_head(&output);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(
+            head.contains("\r\nAllow: GET, HEAD, POST, PUT, DELETE, OPTIONS\r\n"),
+            "{head}"
+        );
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn trace_echoes_the_request_only_when_allowed() {
+        let root = TempDir::new();
+        let request = b"TRACE /debug HTTP/1.1\r\nX-Probe: 1\r\nCookie: session=abc\r\n\
+                        Connection: close\r\n\r\n";
+
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+        let output = exchange(&state, request).unwrap();
+        let (head, _) = split_head(&output);
+        assert!(
+            head.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+            "{head}"
+        );
+        assert!(head.contains("\r\nAllow: GET, HEAD, POST, PUT, DELETE, OPTIONS\r\n"));
+
+        let config = ServerConfig {
+            allow_trace: true,
+            ..test_config(root.path())
+        };
+        let state = test_state(config.clone(), default_router(&config));
+        let output = exchange(&state, request).unwrap();
+        let (head, body) = split_head(&output);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains("Content-Type: message/http\r\n"), "{head}");
+        let body = std::str::from_utf8(body).unwrap();
+        assert!(body.starts_with("TRACE /debug HTTP/1.1\r\n"), "{body}");
+        assert!(body.contains("\r\nX-Probe: 1\r\n"), "{body}");
+        assert!(!body.contains("session=abc"), "{body}");
+        assert!(body.ends_with("\r\n\r\n"), "{body}");
+
+        let output = exchange(&state, b"OPTIONS * HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let (head, _) = split_head(&output);
+        assert!(head.contains("\r\nAllow: GET, HEAD, POST, PUT, DELETE, OPTIONS, TRACE\r\n"));
+    }
+
+    #[test]
+    fn folded_header_is_a_bad_request() {
+        let root = TempDir::new();
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+        let (output, err) = exchange_rejected(
+            &state,
+            b"GET / HTTP/1.1\r\nX-Long: first\r\n second\r\n\r\n",
+        );
+        assert!(
+            output.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{output}"
+        );
+        assert!(matches!(
+            err,
+            ConnectionError::Parse(ParseError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn connection_closes_after_keep_alive_max_requests() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let config = ServerConfig {
+            keep_alive_max_requests: Some(2),
+            ..local_config(root.path())
+        };
+        let handle = Server::bind(config).unwrap().run();
+
+        // `send` reads until the server closes, so it only returns because
+        // the limit ended the connection.
+        let response = send(
+            handle.local_addrs()[0],
+            "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+        );
+        let responses: Vec<_> = response.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+        assert_eq!(responses.len(), 2, "{response}");
+        assert!(
+            responses[0].contains("Connection: keep-alive\r\n"),
+            "{response}"
+        );
+        assert!(responses[1].contains("Connection: close\r\n"), "{response}");
+        handle.shutdown();
+    }
+
+    #[test]
+    fn bind_rejects_a_missing_or_non_directory_root() {
+        let root = TempDir::new();
+        let missing = root.path().join("missing");
+        let err = Server::bind(local_config(&missing))
+            .err()
+            .expect("bind should fail");
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(
+            err.to_string().contains(&missing.display().to_string()),
+            "{err}"
+        );
+
+        let file = root.write("file.txt", "not a directory");
+        let err = Server::bind(local_config(&file))
+            .err()
+            .expect("bind should fail");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("is not a directory"), "{err}");
+    }
+
+    #[test]
+    fn custom_not_found_handler_answers_unmatched_paths() {
+        let root = TempDir::new();
+        root.write("index.html", "<h1>hi</h1>");
+        let config = ServerConfig {
+            not_found_handler: Some(NotFoundHandler::new(|request| {
+                Response::new(StatusCode::NOT_FOUND)
+                    .header("Content-Type", "application/json")
+                    .body(format!("{{\"error\":\"no route for {}\"}}", request.path))
+            })),
+            ..test_config(root.path())
+        };
+        let state = test_state(config.clone(), default_router(&config));
+
+        for request in [
+            &b"GET /api/nope HTTP/1.1\r\nConnection: close\r\n\r\n"[..],
+            b"DELETE /api/nope HTTP/1.1\r\nConnection: close\r\n\r\n",
+        ] {
+            let output = exchange(&state, request).unwrap();
+            let (head, body) = split_head(&output);
+            assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"), "{head}");
+            assert!(
+                head.contains("Content-Type: application/json\r\n"),
+                "{head}"
+            );
+            assert_eq!(body, br#"{"error":"no route for /api/nope"}"#);
+        }
+
+        let output = exchange(&state, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(output.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn chunked_uploads_are_read_and_malformed_ones_rejected() {
+        let root = TempDir::new();
+        let config = test_config(root.path());
+        let state = test_state(config.clone(), default_router(&config));
+
+        let output = exchange(
+            &state,
+            b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+              3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        let (head, body) = split_head(&output);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert_eq!(body, b"abcdef");
+
+        let (output, _) = exchange_rejected(
+            &state,
+            b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nxyz\r\nabc\r\n0\r\n\r\n",
+        );
+        assert!(
+            output.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn handlers_see_the_client_address() {
+        let root = TempDir::new();
+        let whoami = || {
+            let mut router = Router::new();
+            router.route(Method::Get, "/whoami", |request| {
+                Response::new(StatusCode::OK).body(request.remote_addr.to_string())
+            });
+            router
+        };
+        let handle = Server::bind_with_router(local_config(root.path()), whoami())
+            .unwrap()
+            .run();
+
+        let mut client = std::net::TcpStream::connect(handle.local_addrs()[0]).unwrap();
+        let client_addr = client.local_addr().unwrap();
+        client
+            .write_all(b"GET /whoami HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(
+            response.ends_with(&format!("\r\n\r\n{client_addr}")),
+            "{response}"
+        );
+        handle.shutdown();
+
+        // Without a peer address, as on a Unix socket, it stays unspecified.
+        let state = test_state(test_config(root.path()), whoami());
+        let output =
+            exchange(&state, b"GET /whoami HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(output.ends_with(b"\r\n\r\n0.0.0.0:0"));
+    }
+
+    #[test]
+    fn forwarded_for_is_only_believed_from_trusted_proxies() {
+        let root = TempDir::new();
+        let config = ServerConfig {
+            trusted_proxies: vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            ..test_config(root.path())
+        };
+        let client = |peer: &str, forwarded: &[&str]| {
+            let mut raw = "GET / HTTP/1.1\r\n".to_string();
+            for value in forwarded {
+                raw.push_str(&format!("X-Forwarded-For: {value}\r\n"));
+            }
+            raw.push_str("\r\n");
+            let request = Request::parse(&mut raw.as_bytes()).unwrap();
+            forwarded_client(peer.parse().unwrap(), &request, &config).to_string()
+        };
+
+        assert_eq!(client("10.0.0.1:4000", &["203.0.113.9"]), "203.0.113.9:0");
+        // Hops are skipped from the right while they're trusted proxies, and
+        // whatever the client claimed further left is ignored.
+        assert_eq!(
+            client("10.0.0.1:4000", &["192.0.2.66, 203.0.113.9", "10.0.0.2"]),
+            "203.0.113.9:0"
+        );
+        assert_eq!(client("10.0.0.1:4000", &[]), "10.0.0.1:4000");
+        assert_eq!(
+            client("198.51.100.7:4000", &["203.0.113.9"]),
+            "198.51.100.7:4000"
+        );
+    }
+
+    /// The rightmost untrusted hop is the client, not the leftmost: the
+    /// leftmost is whatever the client itself chose to send.
+    #[test]
+    fn forwarded_for_picks_the_hop_before_the_trusted_proxies() {
+        let root = TempDir::new();
+        let config = ServerConfig {
+            trusted_proxies: vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            ..test_config(root.path())
+        };
+        let raw = "GET / HTTP/1.1\r\nX-Forwarded-For: 192.0.2.66, 203.0.113.9, 10.0.0.2\r\n\r\n";
+        let request = Request::parse(&mut raw.as_bytes()).unwrap();
+        let peer = "10.0.0.1:4000".parse().unwrap();
+        assert_eq!(
+            forwarded_client(peer, &request, &config),
+            "203.0.113.9:0".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn too_many_headers_get_431() {
+        let root = TempDir::new();
+        let config = ServerConfig {
+            max_headers: 100,
+            ..test_config(root.path())
+        };
+        let state = test_state(config.clone(), default_router(&config));
+        let headers: String = (0..200).map(|i| format!("X-{i}: y\r\n")).collect();
+        let input = format!("GET / HTTP/1.1\r\n{headers}\r\n");
+
+        let (output, err) = exchange_rejected(&state, input.as_bytes());
+        assert!(
+            output.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+            "{output}"
+        );
+        assert!(
+            matches!(err, ConnectionError::Parse(ParseError::TooManyHeaders)),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn bind_names_the_address_it_could_not_bind() {
+        let root = TempDir::new();
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+
+        let config = ServerConfig {
+            addr,
+            ..test_config(root.path())
+        };
+        let err = Server::bind(config).err().expect("bind should fail");
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        assert!(
+            err.to_string()
+                .starts_with(&format!("failed to bind {addr}: ")),
+            "{err}"
+        );
+
+        let config = ServerConfig {
+            extra_addrs: vec![addr],
+            ..local_config(root.path())
+        };
+        let err = Server::bind(config).err().expect("bind should fail");
+        assert!(
+            err.to_string()
+                .starts_with(&format!("failed to bind {addr}: ")),
+            "{err}"
+        );
+    }
+}
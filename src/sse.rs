@@ -0,0 +1,122 @@
+//! Server-Sent Events: a response that stays open and delivers events as
+//! the handler produces them.
+
+use std::{
+    io::{self, Read},
+    sync::mpsc,
+};
+
+use crate::{response::Response, status::StatusCode};
+
+/// The sending half of an event stream, see `SseStream::open`.
+#[derive(Debug, Clone)]
+pub struct SseStream {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl SseStream {
+    /// Creates a stream and the `text/event-stream` response a handler
+    /// returns to deliver it.
+    ///
+    /// The handler hands the `SseStream` to a thread of its own that calls
+    /// `send` for every event. The connection stays open, and keeps its
+    /// worker busy, until every clone of the stream is dropped.
+    pub fn open() -> (SseStream, Response) {
+        let (tx, rx) = mpsc::channel();
+        let response = Response::new(StatusCode::OK)
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .chunked(EventReader {
+                rx,
+                pending: Vec::new(),
+                pos: 0,
+            });
+        (SseStream { tx }, response)
+    }
+
+    /// Sends `event` as one `data` event, split into one `data:` line per
+    /// line of text. Fails with `BrokenPipe` once the client has gone away.
+    pub fn send(&self, event: &str) -> io::Result<()> {
+        let mut message = String::new();
+        for line in event.lines() {
+            message.push_str("data: ");
+            message.push_str(line);
+            message.push('\n');
+        }
+        if message.is_empty() {
+            message.push_str("data:\n");
+        }
+        message.push('\n');
+        self.tx.send(message.into_bytes()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "event stream client disconnected",
+            )
+        })
+    }
+}
+
+/// The response body: blocks until the next event and ends once every
+/// `SseStream` is dropped.
+struct EventReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for EventReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.pending.len() {
+            match self.rx.recv() {
+                Ok(event) => {
+                    self.pending = event;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.pos);
+        buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::test_support::{dechunk, split_head};
+
+    #[test]
+    fn delivers_events_until_the_stream_is_dropped() {
+        let (events, mut response) = SseStream::open();
+        let sender = thread::spawn(move || {
+            events.send("first").unwrap();
+            events.send("second\nline").unwrap();
+        });
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        sender.join().unwrap();
+
+        let (head, body) = split_head(&out);
+        assert!(
+            head.contains("Content-Type: text/event-stream\r\n"),
+            "{head}"
+        );
+        assert_eq!(
+            String::from_utf8(dechunk(body)).unwrap(),
+            "data: first\n\ndata: second\ndata: line\n\n"
+        );
+    }
+
+    #[test]
+    fn send_fails_once_the_client_is_gone() {
+        let (events, response) = SseStream::open();
+        drop(response);
+        let err = events.send("lost").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,176 @@
+//! The WebSocket opening handshake (RFC 6455). Framing is left to the
+//! callback that receives the upgraded connection.
+
+use std::{
+    io::{self, Read, Write},
+    net::SocketAddr,
+};
+
+use crate::{
+    base64,
+    listener::Connection,
+    request::{Method, Request},
+    response::Response,
+    sha1::sha1,
+    status::StatusCode,
+};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The `Sec-WebSocket-Accept` value answering a `Sec-WebSocket-Key`.
+pub fn websocket_accept_key(key: &str) -> String {
+    let mut input = key.trim().as_bytes().to_vec();
+    input.extend_from_slice(GUID.as_bytes());
+    base64::encode(&sha1(&input))
+}
+
+/// Answers a WebSocket upgrade request with `101 Switching Protocols` and
+/// passes the connection to `on_upgrade` once the response is sent.
+///
+/// Requests that aren't a valid version 13 handshake get `400 Bad Request`,
+/// or `426 Upgrade Required` naming the supported version. `on_upgrade`
+/// runs on the connection's worker thread and keeps it busy until it
+/// returns.
+pub fn accept_websocket<F>(request: &Request, on_upgrade: F) -> Response
+where
+    F: FnOnce(Upgraded) + Send + 'static,
+{
+    let has_token = |name: &str, token: &str| {
+        request.header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token))
+        })
+    };
+
+    let key = request
+        .header("Sec-WebSocket-Key")
+        .filter(|key| base64::decode(key.trim()).is_some_and(|nonce| nonce.len() == 16));
+    let (Some(key), true, true, true) = (
+        key,
+        request.method == Method::Get,
+        has_token("Upgrade", "websocket"),
+        has_token("Connection", "upgrade"),
+    ) else {
+        return Response::error(StatusCode::BAD_REQUEST);
+    };
+    if request.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Response::error(StatusCode::UPGRADE_REQUIRED).header("Sec-WebSocket-Version", "13");
+    }
+
+    Response::new(StatusCode::SWITCHING_PROTOCOLS)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", websocket_accept_key(key))
+        .upgrade(on_upgrade)
+}
+
+/// A connection that has switched protocols, readable and writable as raw
+/// bytes.
+pub struct Upgraded {
+    /// Bytes the client sent after the handshake that were already read
+    /// into the request buffer.
+    buffered: Vec<u8>,
+    pos: usize,
+    stream: Box<dyn Connection>,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl Upgraded {
+    pub(crate) fn new(
+        buffered: Vec<u8>,
+        stream: Box<dyn Connection>,
+        peer_addr: Option<SocketAddr>,
+    ) -> Self {
+        Upgraded {
+            buffered,
+            pos: 0,
+            stream,
+            peer_addr,
+        }
+    }
+
+    /// The client's address, or `None` on a Unix socket.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.buffered.len() {
+            let n = buf.len().min(self.buffered.len() - self.pos);
+            buf[..n].copy_from_slice(&self.buffered[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The handshake from RFC 6455, section 1.3, with `extra` headers.
+    fn upgrade_request(extra: &str) -> Request {
+        let raw = format!(
+            "GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{extra}\r\n"
+        );
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn accept_key_matches_the_rfc_example() {
+        assert_eq!(
+            websocket_accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn valid_handshake_switches_protocols() {
+        let request = upgrade_request("Sec-WebSocket-Version: 13\r\n");
+        let response = accept_websocket(&request, |_| {});
+        assert_eq!(response.status, StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(response.header_value("Upgrade"), Some("websocket"));
+        assert_eq!(
+            response.header_value("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+    }
+
+    #[test]
+    fn other_versions_are_told_which_one_is_supported() {
+        let request = upgrade_request("Sec-WebSocket-Version: 8\r\n");
+        let response = accept_websocket(&request, |_| {});
+        assert_eq!(response.status, StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(response.header_value("Sec-WebSocket-Version"), Some("13"));
+    }
+
+    #[test]
+    fn malformed_handshakes_are_bad_requests() {
+        let raw = "GET /chat HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                   Sec-WebSocket-Key: c2hvcnQ=\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let request = Request::parse(&mut raw.as_bytes()).unwrap();
+        let response = accept_websocket(&request, |_| {});
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+
+        let raw = "GET /chat HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                   Sec-WebSocket-Version: 13\r\n\r\n";
+        let request = Request::parse(&mut raw.as_bytes()).unwrap();
+        let response = accept_websocket(&request, |_| {});
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    }
+}